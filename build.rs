@@ -0,0 +1,16 @@
+//! Check that the KaTeX assets are there before embedding them.
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=assets/katex");
+    if std::env::var_os("CARGO_FEATURE_EMBED_ASSETS").is_none() {
+        return;
+    }
+    let version = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/katex/KATEX-VERSION");
+    if !version.is_file() {
+        panic!(
+            "The `embed-assets` feature needs the KaTeX stylesheet and fonts in `assets/katex`. \
+             Run `./download_katex_assets.sh` and commit them."
+        );
+    }
+}
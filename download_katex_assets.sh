@@ -0,0 +1,23 @@
+#!/usr/bin/env bash
+# Download the KaTeX stylesheet and fonts embedded by the `embed-assets` feature.
+# The version is that of the KaTeX bundled in the `katex` crate,
+# which the build checks the assets against.
+# Commit the result, so that the assets are packaged with the crate.
+set -euo pipefail
+
+ROOT="$(cd "$(dirname "$0")" && pwd)"
+KATEX_CRATE="$(cargo metadata --format-version 1 --manifest-path "$ROOT/Cargo.toml" |
+    jq -r '.packages[] | select(.name == "katex") | .manifest_path')"
+KATEX_VERSION="$(cat "$(dirname "$KATEX_CRATE")/KATEX-VERSION")"
+DIR="$ROOT/assets/katex"
+TMP="$(mktemp -d)"
+trap 'rm -rf "$TMP"' EXIT
+
+curl -sSfL "https://registry.npmjs.org/katex/-/katex-$KATEX_VERSION.tgz" |
+    tar -xz -C "$TMP"
+
+rm -rf "$DIR"
+mkdir -p "$DIR/fonts"
+cp "$TMP/package/dist/katex.min.css" "$DIR/"
+cp "$TMP"/package/dist/fonts/*.woff2 "$DIR/fonts/"
+printf '%s' "$KATEX_VERSION" >"$DIR/KATEX-VERSION"
@@ -0,0 +1,129 @@
+//! KaTeX stylesheet and fonts bundled in the binary.
+use super::*;
+
+/// Directory, relative to the book's `src`, the embedded assets are written to.
+pub const ASSETS_DIR: &str = "katex";
+
+/// Path of the stylesheet inside `ASSETS_DIR`.
+pub const ASSETS_STYLESHEET: &str = "katex.min.css";
+
+/// Embed the `.woff2` file of KaTeX font `$name`.
+#[cfg(feature = "embed-assets")]
+macro_rules! embed_font {
+    ($name:literal) => {
+        (
+            concat!("fonts/", $name, ".woff2"),
+            include_bytes!(concat!("../assets/katex/fonts/", $name, ".woff2")),
+        )
+    };
+}
+
+/// KaTeX stylesheet and fonts, as `(path inside ASSETS_DIR, content)`.
+/// Populated by `download_katex_assets.sh`.
+#[cfg(feature = "embed-assets")]
+pub const EMBEDDED_ASSETS: &[(&str, &[u8])] = &[
+    (
+        ASSETS_STYLESHEET,
+        include_bytes!("../assets/katex/katex.min.css"),
+    ),
+    embed_font!("KaTeX_AMS-Regular"),
+    embed_font!("KaTeX_Caligraphic-Bold"),
+    embed_font!("KaTeX_Caligraphic-Regular"),
+    embed_font!("KaTeX_Fraktur-Bold"),
+    embed_font!("KaTeX_Fraktur-Regular"),
+    embed_font!("KaTeX_Main-Bold"),
+    embed_font!("KaTeX_Main-BoldItalic"),
+    embed_font!("KaTeX_Main-Italic"),
+    embed_font!("KaTeX_Main-Regular"),
+    embed_font!("KaTeX_Math-BoldItalic"),
+    embed_font!("KaTeX_Math-Italic"),
+    embed_font!("KaTeX_SansSerif-Bold"),
+    embed_font!("KaTeX_SansSerif-Italic"),
+    embed_font!("KaTeX_SansSerif-Regular"),
+    embed_font!("KaTeX_Script-Regular"),
+    embed_font!("KaTeX_Size1-Regular"),
+    embed_font!("KaTeX_Size2-Regular"),
+    embed_font!("KaTeX_Size3-Regular"),
+    embed_font!("KaTeX_Size4-Regular"),
+    embed_font!("KaTeX_Typewriter-Regular"),
+];
+
+/// Version of the KaTeX release the embedded assets come from.
+#[cfg(feature = "embed-assets")]
+pub const EMBEDDED_ASSETS_VERSION: &str = include_str!("../assets/katex/KATEX-VERSION");
+
+/// Whether `a` and `b` are equal, in constant contexts.
+#[cfg(all(feature = "embed-assets", feature = "pre-render"))]
+const fn same_str(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[cfg(all(feature = "embed-assets", feature = "pre-render"))]
+const _: () = assert!(
+    same_str(EMBEDDED_ASSETS_VERSION, katex::KATEX_VERSION),
+    "The embedded KaTeX assets are not those of the KaTeX used for pre-rendering. Run `./download_katex_assets.sh` again."
+);
+
+/// Write the embedded assets into `dir`.
+#[cfg(feature = "embed-assets")]
+pub fn write_embedded_assets(dir: &Path) -> Result<()> {
+    for (path, content) in EMBEDDED_ASSETS {
+        write_if_changed(&dir.join(path), content)?;
+    }
+    Ok(())
+}
+
+/// When `assets = "embed"` is set but not enabled.
+#[cfg(not(feature = "embed-assets"))]
+pub fn write_embedded_assets(_: &Path) -> Result<()> {
+    Err(mdbook_preprocessor::errors::Error::msg("Embedded KaTeX assets are unavailable because this `mdbook-katex` program does not have the `embed-assets` feature enabled. Either set `assets = \"cdn\"`, or add the `embed-assets` feature and recompile. See the README at <https://github.com/lzanini/mdbook-katex/blob/master/README.md>."))
+}
+
+/// Write `content` to `path`, creating parent directories as needed.
+/// Files whose content is already up to date are left untouched,
+/// so that `mdbook serve` does not pick them up as changes.
+pub fn write_if_changed(path: &Path, content: &[u8]) -> Result<()> {
+    if std::fs::read(path).is_ok_and(|existing| existing == content) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, content)?;
+    debug!(?path, "Wrote KaTeX asset.");
+    Ok(())
+}
+
+/// Link to the embedded stylesheet for the chapter at `chapter_path`.
+/// The relative link is rewritten from mdBook's `path_to_root`, if defined,
+/// so that it also works in `print.html`, where chapters are served from the root.
+pub fn embedded_stylesheet_link(chapter_path: Option<&Path>) -> String {
+    let href = format!("{ASSETS_DIR}/{ASSETS_STYLESHEET}");
+    format!(
+        r#"<link rel="stylesheet" href="{}{href}">
+<script>if (typeof path_to_root === "string") document.currentScript.previousElementSibling.href = path_to_root + "{href}";</script>
+
+"#,
+        path_to_root(chapter_path)
+    )
+}
+
+/// Relative path from the HTML page of the chapter at `chapter_path`
+/// back to the root of the book.
+pub fn path_to_root(chapter_path: Option<&Path>) -> String {
+    let depth = chapter_path
+        .and_then(Path::parent)
+        .map_or(0, |parent| parent.components().count());
+    "../".repeat(depth)
+}
@@ -0,0 +1,195 @@
+//! Persistent on-disk cache of rendered formulas, shared between builds.
+//!
+//! Entries are content-addressed: each lives in a file named after the hash
+//! of the formula, its display mode, and a fingerprint of the KaTeX options,
+//! macros and version it was rendered with.
+//! Entries are written to a temporary file then renamed into place,
+//! so concurrent builds never see partial entries.
+use std::{
+    fs,
+    io::{self, Write},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+
+use super::*;
+
+/// First line of each cache entry, changed whenever the format changes.
+pub const CACHE_FORMAT: &str = "mdbook-katex-cache-v1\n";
+
+/// Counter making temporary file names unique within the process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Cache of rendered formulas in a directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RenderCache {
+    /// Directory holding the entries.
+    pub dir: PathBuf,
+    /// Total size of the entries above which the oldest are evicted, in bytes.
+    pub max_size: u64,
+    /// Hash of everything besides the formula that affects rendering.
+    pub fingerprint: String,
+}
+
+/// Hexadecimal SHA-256 hash of `parts`, each followed by a NUL byte.
+fn hash(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+impl RenderCache {
+    /// Cache in `dir` limited to `max_size` bytes, for formulas rendered
+    /// with the options serialized as `options`.
+    pub fn new(dir: PathBuf, max_size: u64, options: &str) -> Self {
+        Self {
+            dir,
+            max_size,
+            fingerprint: hash(&[options]),
+        }
+    }
+
+    /// The same cache, for formulas rendered with `macros`,
+    /// as names and KaTeX expansions, added to the options.
+    pub fn with_macros(&self, macros: &[(String, String)]) -> Self {
+        if macros.is_empty() {
+            return self.clone();
+        }
+        let mut parts = vec![self.fingerprint.as_str()];
+        for (name, expansion) in macros {
+            parts.push(name);
+            parts.push(expansion);
+        }
+        Self {
+            fingerprint: hash(&parts),
+            ..self.clone()
+        }
+    }
+
+    /// Key of the formula `item`, displayed or inline.
+    pub fn key(&self, item: &str, display: bool) -> String {
+        let mode = match display {
+            true => "display",
+            false => "inline",
+        };
+        hash(&[&self.fingerprint, mode, item])
+    }
+
+    /// Path of the entry for `key`, in a subdirectory named after its
+    /// first two characters to keep directories small.
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(&key[..2]).join(&key[2..])
+    }
+
+    /// Rendered HTML stored for `key`, if any.
+    /// Marks the entry as recently used, to be evicted last.
+    pub fn get(&self, key: &str) -> Option<String> {
+        let path = self.path(key);
+        let entry = fs::read_to_string(&path).ok()?;
+        let rendered = entry.strip_prefix(CACHE_FORMAT)?;
+        if let Err(why) = fs::File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            debug!(?path, %why, "Couldn't mark a cache entry as used.");
+        }
+        Some(rendered.to_owned())
+    }
+
+    /// Store `rendered` for `key`. Failing to is only logged,
+    /// as the cache is an optimization.
+    pub fn put(&self, key: &str, rendered: &str) {
+        if let Err(why) = self.try_put(key, rendered) {
+            warn!(dir = ?self.dir, %why, "Couldn't write to the render cache.");
+        }
+    }
+
+    /// Write the entry for `key` to a temporary file, then rename it into place
+    /// so readers see either no entry or a complete one.
+    fn try_put(&self, key: &str, rendered: &str) -> io::Result<()> {
+        let path = self.path(key);
+        let dir = path.parent().expect("Entries are in subdirectories");
+        fs::create_dir_all(dir)?;
+        let temp = dir.join(format!(
+            ".{}.{}.{}.tmp",
+            &key[2..],
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let written = fs::File::create(&temp).and_then(|mut file| {
+            file.write_all(CACHE_FORMAT.as_bytes())?;
+            file.write_all(rendered.as_bytes())
+        });
+        match written.and_then(|_| fs::rename(&temp, &path)) {
+            Ok(()) => Ok(()),
+            Err(why) => {
+                _ = fs::remove_file(&temp);
+                Err(why)
+            }
+        }
+    }
+
+    /// Evict the least recently used entries until they total
+    /// at most `max_size` bytes. Return the number of entries evicted.
+    pub fn trim(&self) -> io::Result<usize> {
+        let mut entries = Vec::new();
+        let mut total = 0;
+        let shards = match fs::read_dir(&self.dir) {
+            Ok(shards) => shards,
+            Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(why) => return Err(why),
+        };
+        for shard in shards {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if !metadata.is_file() || entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                total += metadata.len();
+                entries.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+        entries.sort();
+        let mut evicted = 0;
+        for (_, size, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            match fs::remove_file(&path) {
+                // Another build may have evicted it already.
+                Err(why) if why.kind() != io::ErrorKind::NotFound => return Err(why),
+                _ => {
+                    total -= size;
+                    evicted += 1;
+                }
+            }
+        }
+        if evicted > 0 {
+            debug!(evicted, total, "Trimmed the render cache.");
+        }
+        Ok(evicted)
+    }
+}
+
+/// Remove the cache directory `dir` and all its entries, if it exists.
+pub fn clear_cache(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(why) if why.kind() != io::ErrorKind::NotFound => Err(why),
+        _ => Ok(()),
+    }
+}
@@ -1,13 +1,18 @@
 //! Configurations for preprocessing KaTeX.
+use std::time::Duration;
+
+use mdbook_preprocessor::config::Config;
+
 use super::*;
 
 /// Configuration for KaTeX preprocessor,
 /// including options for `katex-rs` and feature options.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct KatexConfig {
     // options for the katex-rust crate
     /// KaTeX output type.
+    #[schemars(extend("enum" = ["html", "mathml", "htmlAndMathml"]))]
     pub output: String,
     /// Whether to have `\tags` rendered on the left instead of the right.
     pub leqno: bool,
@@ -19,7 +24,8 @@ pub struct KatexConfig {
     pub error_color: String,
     /// Specifies a minimum thickness, in ems.
     pub min_rule_thickness: f64,
-    /// Max size for user-specified sizes.
+    /// Max size for user-specified sizes, unlimited by default.
+    #[schemars(transform = remove_default)]
     pub max_size: f64,
     /// Limit the number of macro expansions to the specified number.
     pub max_expand: i32,
@@ -28,16 +34,110 @@ pub struct KatexConfig {
     // other options
     /// Do not inject KaTeX CSS headers.
     pub no_css: bool,
+    /// Where KaTeX CSS and fonts are loaded from.
+    #[schemars(extend("enum" = ["cdn", "embed"]))]
+    pub assets: String,
+    /// Where the KaTeX stylesheet is injected.
+    #[schemars(extend("enum" = ["chapter", "math", "once", "never"]))]
+    pub inject_css: String,
     /// Include math source in rendered HTML.
-    pub include_src: bool,
-    /// Path to macro file.
-    pub macros: Option<String>,
+    pub include_src: IncludeSrc,
+    /// Macros files and tables, merged in order.
+    pub macros: Option<Macros>,
+    /// Embedded macro packages, loaded before `macros`.
+    #[schemars(extend("items" = {"enum": ["physics", "braket", "siunitx-lite"]}))]
+    pub macro_packages: Vec<String>,
+    /// Title of a generated chapter listing the macros, if any.
+    pub notation_chapter: Option<String>,
+    /// Warn about unused macros and undefined control sequences.
+    pub report_macros: bool,
+    /// Path to a configuration file shared between books.
+    pub config_file: Option<String>,
     /// Delimiter for math display block.
     pub block_delimiter: Delimiter,
     /// Delimiter for math inline block.
     pub inline_delimiter: Delimiter,
     /// Use katex.rs to pre-render math equations.
     pub pre_render: bool,
+    /// How math is processed, overriding `pre-render`.
+    #[schemars(extend("enum" = ["pre-render", "escape", "hybrid", null]))]
+    pub mode: Option<String>,
+    /// Base URL of the KaTeX distribution loaded to render the formulas
+    /// that fail to pre-render in hybrid mode.
+    pub fallback_url: String,
+    /// Fail the build if any formula fails to render.
+    pub fail_on_error: bool,
+    /// Maximum length of a formula in bytes, longer ones failing to render.
+    pub max_formula_length: Option<usize>,
+    /// Time limit to render a formula in milliseconds,
+    /// slower ones failing to render.
+    pub render_timeout_ms: Option<u64>,
+    /// How formulas that fail to render are shown.
+    #[schemars(extend("enum" = ["raw", "span", "template"]))]
+    pub error_display: String,
+    /// HTML shown for formulas that fail to render with
+    /// `error-display = "template"`, with `{source}` and `{message}` placeholders.
+    pub error_template: String,
+    /// Carry `\gdef` and other global macro definitions over to
+    /// the following formulas of the chapter.
+    pub persist_macros: bool,
+    /// Reuse formulas rendered by previous builds, stored in `cache-dir`.
+    pub cache: bool,
+    /// Directory of the render cache, relative to the book root.
+    pub cache_dir: String,
+    /// Size of the render cache above which the least recently used
+    /// formulas are evicted, in MiB.
+    pub cache_max_size: u64,
+    /// Number of threads rendering formulas, each with its own KaTeX engine.
+    /// All cores by default.
+    pub jobs: Option<usize>,
+    /// Options overriding the above for specific renderers,
+    /// e.g., `[preprocessor.katex.renderer.epub]`.
+    #[schemars(with = "BTreeMap<String, KatexConfig>")]
+    pub renderer: BTreeMap<String, toml::Table>,
+}
+
+/// Macros configuration, either a single source or a list of sources.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum Macros {
+    /// A list of sources, later ones overriding earlier ones.
+    Many(Vec<MacroSource>),
+    /// A single source.
+    One(MacroSource),
+}
+
+impl Macros {
+    /// All the sources, in order.
+    pub fn sources(&self) -> &[MacroSource] {
+        match self {
+            Macros::Many(sources) => sources,
+            Macros::One(source) => std::slice::from_ref(source),
+        }
+    }
+}
+
+/// Whether and how to include math source in rendered HTML.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum IncludeSrc {
+    /// Whether to include it, in `<data>` tags.
+    Enabled(bool),
+    /// How to include it: `data`, `annotation` or `attribute`.
+    #[schemars(extend("enum" = ["data", "annotation", "attribute"]))]
+    Mode(String),
+}
+
+/// A source of macro definitions.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum MacroSource {
+    /// Path to a macros file, relative to the book root.
+    /// `.tex` files are LaTeX preambles, `.toml` and `.json` files are maps
+    /// from macro names to expansions, and other files use the `.txt` format.
+    Path(String),
+    /// Map from macro names to expansions.
+    Inline(BTreeMap<String, String>),
 }
 
 impl Default for KatexConfig {
@@ -56,27 +156,257 @@ impl Default for KatexConfig {
             trust: false,
             // other options
             no_css: false,
-            include_src: false,
+            assets: "cdn".into(),
+            inject_css: "chapter".into(),
+            include_src: IncludeSrc::Enabled(false),
             macros: None,
+            macro_packages: Vec::new(),
+            notation_chapter: None,
+            report_macros: false,
+            config_file: None,
             block_delimiter: Delimiter::same("$$".into()),
             inline_delimiter: Delimiter::same("$".into()),
             pre_render: true,
+            mode: None,
+            fallback_url: FALLBACK_KATEX_CDN.into(),
+            fail_on_error: false,
+            max_formula_length: None,
+            render_timeout_ms: None,
+            error_display: "raw".into(),
+            error_template: String::new(),
+            persist_macros: false,
+            cache: false,
+            cache_dir: ".katex-cache".into(),
+            cache_max_size: 100,
+            jobs: None,
+            renderer: BTreeMap::new(),
         }
     }
 }
 
 impl KatexConfig {
+    /// Configured source of the KaTeX stylesheet.
+    /// Defaults to the CDN, can also be embedded or disabled with `no-css`.
+    pub fn stylesheet(&self) -> Stylesheet {
+        if self.no_css {
+            return Stylesheet::None;
+        }
+        self.try_stylesheet().unwrap_or_else(|why| {
+            error!("{why}\nDefaulting to `cdn`.");
+            Stylesheet::Cdn
+        })
+    }
+
+    /// Configured source of the KaTeX stylesheet, ignoring `no-css`.
+    fn try_stylesheet(&self) -> Result<Stylesheet, String> {
+        match self.assets.as_str() {
+            "cdn" => Ok(Stylesheet::Cdn),
+            "embed" => Ok(Stylesheet::Embedded),
+            other => Err(invalid_choice(other, "assets", "`cdn` and `embed`")),
+        }
+    }
+
+    /// Configured injection of the KaTeX stylesheet.
+    /// Defaults to every chapter, can also be `math`, `once` or `never`.
+    pub fn css_injection(&self) -> CssInjection {
+        self.try_css_injection().unwrap_or_else(|why| {
+            error!("{why}\nDefaulting to `chapter`.");
+            CssInjection::Chapter
+        })
+    }
+
+    /// Configured injection of the KaTeX stylesheet, if valid.
+    fn try_css_injection(&self) -> Result<CssInjection, String> {
+        match self.inject_css.as_str() {
+            "chapter" => Ok(CssInjection::Chapter),
+            "math" => Ok(CssInjection::Math),
+            "once" => Ok(CssInjection::Once),
+            "never" => Ok(CssInjection::Never),
+            other => Err(invalid_choice(
+                other,
+                "inject-css",
+                "`chapter`, `math`, `once` and `never`",
+            )),
+        }
+    }
+
+    /// Configured processing of math.
+    /// Follows `pre-render` by default, can also be `hybrid`.
+    pub fn mode(&self) -> Mode {
+        self.try_mode().unwrap_or_else(|why| {
+            error!("{why}\nDefaulting to `pre-render`.");
+            Mode::PreRender
+        })
+    }
+
+    /// Configured processing of math, if valid.
+    fn try_mode(&self) -> Result<Mode, String> {
+        let Some(mode) = &self.mode else {
+            return match self.pre_render {
+                true => Ok(Mode::PreRender),
+                false => Ok(Mode::Escape),
+            };
+        };
+        match mode.as_str() {
+            "pre-render" => Ok(Mode::PreRender),
+            "escape" => Ok(Mode::Escape),
+            "hybrid" => Ok(Mode::Hybrid),
+            other => Err(invalid_choice(
+                other,
+                "mode",
+                "`pre-render`, `escape` and `hybrid`",
+            )),
+        }
+    }
+
+    /// Configured inclusion of math source in rendered HTML.
+    /// Defaults to none, can also be `data` (or `true`), `annotation` or `attribute`.
+    pub fn source_inclusion(&self) -> SourceInclusion {
+        self.try_source_inclusion().unwrap_or_else(|why| {
+            error!("{why}\nDefaulting to `false`.");
+            SourceInclusion::None
+        })
+    }
+
+    /// Configured inclusion of math source in rendered HTML, if valid.
+    fn try_source_inclusion(&self) -> Result<SourceInclusion, String> {
+        match &self.include_src {
+            IncludeSrc::Enabled(false) => Ok(SourceInclusion::None),
+            IncludeSrc::Enabled(true) => Ok(SourceInclusion::Data),
+            IncludeSrc::Mode(mode) => match mode.as_str() {
+                "data" => Ok(SourceInclusion::Data),
+                "annotation" => Ok(SourceInclusion::Annotation),
+                "attribute" => Ok(SourceInclusion::Attribute),
+                other => Err(invalid_choice(
+                    other,
+                    "include-src",
+                    "`false`, `true`, `data`, `annotation` and `attribute`",
+                )),
+            },
+        }
+    }
+
+    /// Configured presentation of formulas that fail to render.
+    /// Defaults to `raw`, can also be `span` or `template`.
+    pub fn error_display(&self) -> ErrorDisplay {
+        self.try_error_display().unwrap_or_else(|why| {
+            error!("{why}\nDefaulting to `raw`.");
+            ErrorDisplay::Raw
+        })
+    }
+
+    /// Configured presentation of formulas that fail to render, if valid.
+    fn try_error_display(&self) -> Result<ErrorDisplay, String> {
+        match self.error_display.as_str() {
+            "raw" => Ok(ErrorDisplay::Raw),
+            "span" => Ok(ErrorDisplay::Span {
+                color: self.error_color.clone(),
+            }),
+            "template" if self.error_template.is_empty() => Err(
+                "[preprocessor.katex]: `error-display = \"template\"` requires `error-template`!"
+                    .into(),
+            ),
+            "template" => Ok(ErrorDisplay::Template(self.error_template.clone())),
+            other => Err(invalid_choice(
+                other,
+                "error-display",
+                "`raw`, `span` and `template`",
+            )),
+        }
+    }
+
+    /// Check the options with a fixed set of valid choices.
+    /// `error-template` is only checked if `with_template`,
+    /// as it may be set elsewhere than `error-display`.
+    pub fn check_choices(&self, with_template: bool) -> Result<(), String> {
+        self.try_stylesheet()?;
+        self.try_css_injection()?;
+        self.try_mode()?;
+        self.try_source_inclusion()?;
+        match self.try_error_display() {
+            Err(_) if !with_template && self.error_display == "template" => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
     /// Generate extra options for the preprocessor.
     pub fn build_extra_opts(&self) -> ExtraOpts {
         ExtraOpts {
-            include_src: self.include_src,
+            include_src: self.source_inclusion(),
+            persist_macros: self.persist_macros,
             block_delimiter: self.block_delimiter.clone(),
             inline_delimiter: self.inline_delimiter.clone(),
+            error_display: match self.mode() {
+                Mode::Hybrid => ErrorDisplay::Fallback,
+                Mode::PreRender | Mode::Escape => self.error_display(),
+            },
+            max_formula_length: self.max_formula_length,
+            render_timeout: self.render_timeout_ms.map(Duration::from_millis),
+            fallback_url: self.fallback_url.trim_end_matches('/').into(),
+            cache: None,
         }
     }
+
+    /// Directory of the render cache of the book at `root`.
+    pub fn cache_dir(&self, root: &Path) -> PathBuf {
+        root.join(&self.cache_dir)
+    }
+
+    /// From `root`, load the definitions of `macro-packages` then `macros`,
+    /// so user macros override packaged ones, and split out the environments.
+    pub fn load_definitions<P>(&self, root: P) -> Result<(Vec<MacroDefinition>, Environments)>
+    where
+        P: AsRef<Path>,
+    {
+        let mut definitions = load_macro_packages(&self.macro_packages)?;
+        definitions.extend(load_macro_definitions(root, &self.macros)?);
+        Ok(split_environments(definitions))
+    }
+
+    /// From `root`, load the environments of `macro-packages` and `macros`,
+    /// for escape mode, where macros are not used.
+    /// Invalid macros are only logged, and their environments left out.
+    pub fn load_environments<P>(&self, root: P) -> Environments
+    where
+        P: AsRef<Path>,
+    {
+        match self.load_definitions(root) {
+            Ok((_, environments)) => environments,
+            Err(why) => {
+                warn!("{why}\nIgnoring the environments in `macros`.");
+                split_environments(load_macro_packages(&self.macro_packages).unwrap_or_default()).1
+            }
+        }
+    }
+}
+
+/// Message for the invalid `value` of `option`, with its `valid` choices.
+fn invalid_choice(value: &str, option: &str, valid: &str) -> String {
+    format!(
+        "[preprocessor.katex]: `{value}` is not a valid choice for `{option}`! Please check your `book.toml`.
+Valid choices for {option} are {valid}."
+    )
+}
+
+/// Remove the default of `schema`, which JSON cannot represent,
+/// e.g., infinity.
+fn remove_default(schema: &mut schemars::Schema) {
+    schema.remove("default");
+}
+
+/// JSON Schema of the configuration under `[preprocessor.katex]`.
+pub fn config_schema() -> schemars::Schema {
+    schemars::schema_for!(KatexConfig)
 }
 
-/// Extract configuration for katex preprocessor from `book_cfg`.
+/// Prefix of environment variables overriding the configuration.
+pub const ENV_PREFIX: &str = "MDBOOK_KATEX_";
+
+/// Extract configuration for katex preprocessor from `book_cfg`,
+/// falling back to defaults if it is invalid.
+#[deprecated(
+    note = "use `get_context_config`, which also applies `config-file`, renderer profiles and `MDBOOK_KATEX_*` environment variables"
+)]
 pub fn get_config(
     book_cfg: &mdbook_preprocessor::config::Config,
 ) -> Result<KatexConfig, toml::de::Error> {
@@ -89,3 +419,156 @@ pub fn get_config(
     };
     cfg.or_else(|_| Ok(KatexConfig::default()))
 }
+
+/// Extract configuration for katex preprocessor from `ctx`,
+/// with `MDBOOK_KATEX_*` environment variables taking precedence.
+pub fn get_context_config(ctx: &PreprocessorContext) -> Result<KatexConfig> {
+    get_config_with_env(ctx, std::env::vars())
+}
+
+/// Extract configuration for katex preprocessor from `ctx`,
+/// with `MDBOOK_KATEX_*` variables in `vars` taking precedence.
+/// Precedence: `vars` > `[preprocessor.katex.renderer.<ctx.renderer>]`
+/// > `[preprocessor.katex]` > `config-file` > defaults.
+pub fn get_config_with_env<I>(ctx: &PreprocessorContext, vars: I) -> Result<KatexConfig>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let book = match ctx
+        .config
+        .get::<toml::Value>("preprocessor.katex")
+        .unwrap_or_default()
+    {
+        Some(toml::Value::Table(table)) => table,
+        _ => toml::Table::new(),
+    };
+    let mut env = toml::Table::new();
+    apply_env_overrides(&mut env, vars);
+    // Check overrides one by one, to name the variable that is invalid.
+    for (key, value) in &env {
+        let single = toml::Table::from_iter([(key.clone(), value.clone())]);
+        let checked = toml::Value::Table(single)
+            .try_into::<KatexConfig>()
+            .map_err(|why| why.to_string())
+            .and_then(|cfg| cfg.check_choices(false));
+        if let Err(why) = checked {
+            let name = format!("{ENV_PREFIX}{}", key.to_uppercase().replace('-', "_"));
+            return Err(mdbook_preprocessor::errors::Error::msg(format!(
+                "[preprocessor.katex]: invalid value {value} for `{key}` in `{name}`: {why}"
+            )));
+        }
+    }
+
+    let config_file = env.get("config-file").or_else(|| book.get("config-file"));
+    let mut raw = match config_file {
+        Some(toml::Value::String(config_file)) => {
+            load_shared_config(&ctx.root, Path::new(config_file))?
+        }
+        _ => toml::Table::new(),
+    };
+    merge_config(&mut raw, book);
+    if let Some(toml::Value::Table(profile)) = raw
+        .get("renderer")
+        .and_then(|profiles| profiles.get(&ctx.renderer))
+        .cloned()
+    {
+        debug!(ctx.renderer, "Applying renderer profile.");
+        merge_config(&mut raw, profile);
+    }
+    merge_config(&mut raw, env);
+
+    let cfg: KatexConfig = toml::Value::Table(raw).try_into().map_err(|why| {
+        mdbook_preprocessor::errors::Error::msg(format!(
+            "[preprocessor.katex]: invalid configuration: {why}"
+        ))
+    })?;
+    cfg.check_choices(true)
+        .map_err(mdbook_preprocessor::errors::Error::msg)?;
+    check_macro_packages(&cfg.macro_packages)?;
+    debug!(?cfg, "Effective configuration.");
+    Ok(cfg)
+}
+
+/// Configuration of the book at `root` for the `html` renderer,
+/// and the path to its source directory, for subcommands run outside of mdBook.
+pub fn get_book_config(root: &Path) -> Result<(KatexConfig, PathBuf)> {
+    let config = Config::from_disk(root.join("book.toml"))?;
+    let src = root.join(&config.book.src);
+    let ctx = PreprocessorContext::new(root.to_owned(), config, "html".into());
+    Ok((get_context_config(&ctx)?, src))
+}
+
+/// Load the shared configuration file at `root`/`config_file`.
+/// Paths to macros in it, including in renderer profiles, are relative
+/// to `config_file`, so they are rewritten to be relative to `root`.
+pub fn load_shared_config(root: &Path, config_file: &Path) -> Result<toml::Table> {
+    let path = root.join(config_file);
+    let content = std::fs::read_to_string(&path).map_err(|why| {
+        mdbook_preprocessor::errors::Error::msg(format!(
+            "[preprocessor.katex]: couldn't read `config-file` {}: {why}",
+            path.display()
+        ))
+    })?;
+    let mut shared: toml::Table = content.parse().map_err(|why| {
+        mdbook_preprocessor::errors::Error::msg(format!(
+            "[preprocessor.katex]: couldn't parse `config-file` {}: {why}",
+            path.display()
+        ))
+    })?;
+    shared.remove("config-file");
+    let shared_dir = config_file.parent().unwrap_or(Path::new(""));
+    let rebase = |macros: &mut toml::Value| {
+        if let toml::Value::String(path) = macros {
+            *path = shared_dir.join(&*path).to_string_lossy().into_owned();
+        }
+    };
+    let rebase_macros = |table: &mut toml::Table| match table.get_mut("macros") {
+        Some(toml::Value::Array(sources)) => sources.iter_mut().for_each(rebase),
+        Some(macros) => rebase(macros),
+        None => {}
+    };
+    rebase_macros(&mut shared);
+    if let Some(toml::Value::Table(profiles)) = shared.get_mut("renderer") {
+        for (_, profile) in profiles.iter_mut() {
+            if let toml::Value::Table(profile) = profile {
+                rebase_macros(profile);
+            }
+        }
+    }
+    Ok(shared)
+}
+
+/// Merge `over` into `base`, recursively for tables,
+/// with values in `over` taking precedence.
+pub fn merge_config(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge_config(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Override options in `raw` with `vars` named `MDBOOK_KATEX_<OPTION>`,
+/// e.g., `MDBOOK_KATEX_THROW_ON_ERROR=false` sets `throw-on-error`.
+/// Values are parsed as TOML, and used as strings if they are not valid TOML.
+pub fn apply_env_overrides<I>(raw: &mut toml::Table, vars: I)
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    for (name, value) in vars {
+        let Some(option) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = option.to_lowercase().replace('_', "-");
+        let value = format!("value = {value}")
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or(toml::Value::String(value));
+        debug!(key, %value, "Overriding configuration from {name}.");
+        raw.insert(key, value);
+    }
+}
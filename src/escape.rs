@@ -12,6 +12,96 @@ pub fn escape_math_with_delimiter(item: &str, delimiter: &Delimiter) -> String {
     result
 }
 
+/// Class of the elements holding formulas escaped in hybrid mode,
+/// for client-side rendering.
+pub const FALLBACK_CLASS: &str = "katex-fallback";
+
+/// Default base URL of the KaTeX distribution loaded to render fallback
+/// formulas, the same version as the KaTeX stylesheet on the CDN.
+pub const FALLBACK_KATEX_CDN: &str = "https://cdn.jsdelivr.net/npm/katex@0.16.4/dist";
+
+/// `<span>` with the math block `item` escaped with `delimiter`,
+/// for client-side rendering in hybrid mode.
+pub fn fallback_span(item: &str, delimiter: &Delimiter) -> String {
+    format!(
+        r#"<span class="{FALLBACK_CLASS}">{}</span>"#,
+        escape_math_with_delimiter(item, delimiter)
+    )
+}
+
+/// Script loading KaTeX from `extra_opts.fallback_url` to render the formulas
+/// in `FALLBACK_CLASS` elements of a chapter, with `macros` and the delimiters
+/// of `extra_opts`. A relative URL is relative to the root of the book,
+/// found from mdBook's `path_to_root`. KaTeX is loaded once per page,
+/// and each element rendered once, as `print.html` holds all chapters.
+pub fn fallback_bootstrap(macros: &BTreeMap<String, String>, extra_opts: &ExtraOpts) -> String {
+    let delimiters = serde_json::json!([
+        {
+            "left": extra_opts.block_delimiter.left,
+            "right": extra_opts.block_delimiter.right,
+            "display": true,
+        },
+        {
+            "left": extra_opts.inline_delimiter.left,
+            "right": extra_opts.inline_delimiter.right,
+            "display": false,
+        },
+    ]);
+    let options = serde_json::json!({
+        "delimiters": delimiters,
+        "macros": macros,
+        "throwOnError": false,
+    })
+    .to_string()
+    .replace('<', r"\u003c");
+    let url = serde_json::Value::from(extra_opts.fallback_url.as_str())
+        .to_string()
+        .replace('<', r"\u003c");
+    format!(
+        r#"
+
+<script>
+(function () {{
+  const options = {options};
+  function render() {{
+    for (const element of document.querySelectorAll(".{FALLBACK_CLASS}:not([data-rendered])")) {{
+      element.dataset.rendered = "";
+      renderMathInElement(element, options);
+    }}
+  }}
+  function ready() {{
+    if (document.readyState === "loading") {{
+      document.addEventListener("DOMContentLoaded", render);
+    }} else {{
+      render();
+    }}
+  }}
+  if (window.katexFallbackLoaded) {{
+    return window.katexFallbackLoaded.then(ready);
+  }}
+  let url = {url};
+  if (!/^([a-z][a-z0-9+.-]*:|\/)/i.test(url) && typeof path_to_root === "string") {{
+    url = path_to_root + url;
+  }}
+  const load = (src) => new Promise(function (resolve, reject) {{
+    const script = document.createElement("script");
+    script.src = url + src;
+    script.async = false;
+    script.onload = resolve;
+    script.onerror = reject;
+    document.head.appendChild(script);
+  }});
+  window.katexFallbackLoaded = Promise.all([
+    load("/katex.min.js"),
+    load("/contrib/auto-render.min.js"),
+  ]);
+  window.katexFallbackLoaded.then(ready);
+}})();
+</script>
+"#
+    )
+}
+
 /// This is a amazing but useful little trick.
 /// Mdbook's markdown engine will parse a part of KaTeX formula into HTML, e.g. `$[x^n](f + g)$`.
 /// So if we escape the math formula in advance so that it passes through the markdown
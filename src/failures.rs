@@ -0,0 +1,54 @@
+//! Formulas that fail to render, reported together at the end of the build.
+use std::fmt;
+
+use super::*;
+
+/// A formula that failed to render.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RenderFailure {
+    /// Path of the chapter, if it has one.
+    pub chapter: Option<PathBuf>,
+    /// Line where the formula starts in the chapter.
+    pub line: usize,
+    /// Column where the formula starts in the chapter.
+    pub column: usize,
+    /// Source of the formula, as written in the chapter.
+    pub formula: String,
+    /// What KaTeX reported, including the position of the error in the formula.
+    pub message: String,
+}
+
+impl fmt::Display for RenderFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let chapter = self.chapter.as_deref().unwrap_or(Path::new("<unnamed>"));
+        write!(
+            f,
+            "{}:{}:{}: {}\n    {}",
+            chapter.display(),
+            self.line,
+            self.column,
+            self.message,
+            self.formula.replace('\n', "\n    ")
+        )
+    }
+}
+
+/// Log `failures` followed by a summary.
+/// With `fail_on_error`, return the summary as an error instead.
+pub fn report_failures(failures: &[RenderFailure], fail_on_error: bool) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    for failure in failures {
+        warn!("Formula failed to render at {failure}");
+    }
+    let summary = format!(
+        "[preprocessor.katex]: {} formula(s) failed to render, see above.",
+        failures.len()
+    );
+    if fail_on_error {
+        return Err(mdbook_preprocessor::errors::Error::msg(summary));
+    }
+    warn!("{summary}");
+    Ok(())
+}
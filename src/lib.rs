@@ -1,31 +1,45 @@
 #![deny(missing_docs)]
 //! Preprocess math blocks using KaTeX for mdBook.
-use std::{borrow::Cow, collections::VecDeque, io::stderr};
-
-#[cfg(feature = "pre-render")]
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::Read,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    io::stderr,
     path::{Path, PathBuf},
 };
 
+#[cfg(feature = "pre-render")]
+use std::collections::HashMap;
+
 use mdbook_preprocessor::{book::Book, errors::Result, Preprocessor, PreprocessorContext};
 use rayon::iter::*;
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use tracing::*;
 use tracing_subscriber::EnvFilter;
 
 use {
+    assets::*,
+    cache::*,
     cfg::*,
     escape::*,
+    failures::*,
+    macros::*,
+    notation::*,
     preprocess::*,
+    report::*,
     scan::{Event, *},
 };
 
+pub mod assets;
+pub mod cache;
 pub mod cfg;
 pub mod escape;
+pub mod failures;
+pub mod macros;
+pub mod notation;
 pub mod preprocess;
+pub mod rename;
+pub mod report;
 pub mod scan;
 
 #[cfg(feature = "pre-render")]
@@ -0,0 +1,177 @@
+//! Load macro definitions from macros files and tables.
+use std::{error, fmt};
+
+use super::*;
+
+pub use {chapter::*, environments::*, map::*, packages::*, tex::*, txt::*};
+
+mod chapter;
+mod environments;
+mod map;
+mod packages;
+mod tex;
+mod txt;
+
+/// A macro definition.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MacroDefinition {
+    /// Name of the macro, including the backslash, e.g., `\R`.
+    pub name: String,
+    /// Explicitly declared number of arguments.
+    pub n_args: Option<usize>,
+    /// Expansion of the macro.
+    pub expansion: String,
+    /// Line number where the definition starts, or 0 if unknown.
+    pub line: usize,
+    /// Description from the comment after the definition, if any.
+    pub description: Option<String>,
+}
+
+impl MacroDefinition {
+    /// Expansion to hand over to KaTeX.
+    /// KaTeX infers the number of arguments from the highest `#n` in the
+    /// expansion, so unused declared arguments are consumed explicitly.
+    pub fn katex_expansion(&self) -> String {
+        match self.n_args {
+            Some(n_args) if n_args > max_arg(&self.expansion) => {
+                format!(r"\@firstoftwo{{{}}}{{#{n_args}}}", self.expansion)
+            }
+            _ => self.expansion.clone(),
+        }
+    }
+}
+
+/// An error at a line of a macros file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MacroError {
+    /// Path to the macros file.
+    pub path: PathBuf,
+    /// Line number of the error.
+    pub line: usize,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.path.as_os_str().is_empty(), self.line) {
+            (true, line) => write!(f, "line {line}: {}", self.message),
+            (false, 0) => write!(f, "{}: {}", self.path.display(), self.message),
+            (false, line) => write!(f, "{}:{line}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// All errors in a macros file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MacroErrors(pub Vec<MacroError>);
+
+impl fmt::Display for MacroErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid macros file")?;
+        for why in &self.0 {
+            write!(f, "\n{why}")?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for MacroErrors {}
+
+/// Absolute path of the macro file.
+#[deprecated(note = "use `get_macro_paths`, as `macros` may list several sources")]
+pub fn get_macro_path<P>(root: P, macros_path: &Option<String>) -> Option<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    macros_path
+        .as_ref()
+        .map(|path| root.as_ref().join(PathBuf::from(path)))
+}
+
+/// Absolute paths of the macros files.
+pub fn get_macro_paths<P>(root: P, macros: &Option<Macros>) -> Vec<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    macros
+        .iter()
+        .flat_map(Macros::sources)
+        .filter_map(|source| match source {
+            MacroSource::Path(path) => Some(root.as_ref().join(path)),
+            MacroSource::Inline(_) => None,
+        })
+        .collect()
+}
+
+/// Load the definitions from all `macros` sources at `root`, in order.
+/// Definitions from later sources override earlier ones with the same name.
+pub fn load_macro_definitions<P>(root: P, macros: &Option<Macros>) -> Result<Vec<MacroDefinition>>
+where
+    P: AsRef<Path>,
+{
+    let mut definitions: Vec<MacroDefinition> = Vec::new();
+    for source in macros.iter().flat_map(Macros::sources) {
+        let loaded = match source {
+            MacroSource::Path(path) => load_macro_file(&root.as_ref().join(path))?,
+            MacroSource::Inline(map) => map_definitions(map),
+        };
+        for definition in loaded {
+            match definitions.iter_mut().find(|d| d.name == definition.name) {
+                Some(existing) => *existing = definition,
+                None => definitions.push(definition),
+            }
+        }
+    }
+    Ok(definitions)
+}
+
+/// Load the definitions in the macros file at `path`, following its extension.
+pub fn load_macro_file(path: &Path) -> Result<Vec<MacroDefinition>> {
+    let source = std::fs::read_to_string(path).map_err(|why| {
+        mdbook_preprocessor::errors::Error::msg(format!(
+            "[preprocessor.katex]: couldn't read macros file {}: {why}",
+            path.display()
+        ))
+    })?;
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let definitions = match extension {
+        "tex" | "sty" => parse_tex_macros(&source, path)?,
+        "toml" | "json" => parse_map_macros(&source, path)?,
+        _ => parse_macros(&source, path)?,
+    };
+    Ok(definitions)
+}
+
+/// Split `line` at the first `%` not escaped by a backslash
+/// into the code and the comment, if any.
+pub(crate) fn strip_comment(line: &str) -> (&str, Option<&str>) {
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '%' if !escaped => return (&line[..index], Some(&line[index + 1..])),
+            _ => escaped = false,
+        }
+    }
+    (line, None)
+}
+
+/// Description in `comment`, unless it is blank.
+pub(crate) fn description(comment: Option<&str>) -> Option<String> {
+    comment
+        .map(str::trim)
+        .filter(|comment| !comment.is_empty())
+        .map(String::from)
+}
+
+/// Highest argument `#n` used in `expansion`, or 0.
+pub(crate) fn max_arg(expansion: &str) -> usize {
+    expansion
+        .as_bytes()
+        .windows(2)
+        .filter(|pair| pair[0] == b'#' && pair[1].is_ascii_digit())
+        .map(|pair| (pair[1] - b'0') as usize)
+        .max()
+        .unwrap_or(0)
+}
@@ -0,0 +1,222 @@
+//! Macros defined inside chapters, in ```` ```katex-macros ```` fenced blocks
+//! using the `.txt` format.
+use std::ops::Range;
+
+use super::*;
+
+/// Info string of fenced code blocks defining chapter macros.
+pub const CHAPTER_MACROS_INFO: &str = "katex-macros";
+
+/// A `katex-macros` fenced block in a chapter.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChapterMacros {
+    /// Byte range of the whole block, fences included.
+    pub range: Range<usize>,
+    /// Definitions in the block, with line numbers in the chapter.
+    pub definitions: Result<Vec<MacroDefinition>, MacroErrors>,
+}
+
+/// Find all `katex-macros` fenced blocks in `raw_content`.
+/// Other fenced code blocks are skipped, so examples of `katex-macros` blocks
+/// inside them are left untouched, and so are indented code blocks.
+pub fn find_chapter_macros(raw_content: &str) -> Vec<ChapterMacros> {
+    let mut blocks = Vec::new();
+    // Fence, its indentation, whether it opens a `katex-macros` block,
+    // and the byte offsets and line number where the block and its content start.
+    let mut open: Option<(&str, usize, bool, usize, usize, usize)> = None;
+    let mut offset = 0;
+
+    for (index, line) in raw_content.split_inclusive('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len();
+        let fence_line = fence_line(line);
+        match open {
+            Some((fence, indent, is_macros, block_start, content_start, first_line)) => {
+                if fence_line.is_some_and(|(_, rest)| is_closing_fence(rest, fence)) {
+                    if is_macros {
+                        let source = &raw_content[content_start..line_start];
+                        blocks.push(ChapterMacros {
+                            range: block_start..offset,
+                            definitions: parse_block(source, first_line, indent),
+                        });
+                    }
+                    open = None;
+                }
+            }
+            None => {
+                let opening =
+                    fence_line.and_then(|(indent, rest)| Some((indent, opening_fence(rest)?)));
+                if let Some((indent, (fence, info))) = opening {
+                    let is_macros = info == CHAPTER_MACROS_INFO;
+                    open = Some((fence, indent, is_macros, line_start, offset, index + 2));
+                }
+            }
+        }
+    }
+    // An unclosed fenced block extends to the end of the chapter.
+    if let Some((_, indent, true, block_start, content_start, first_line)) = open {
+        blocks.push(ChapterMacros {
+            range: block_start..raw_content.len(),
+            definitions: parse_block(&raw_content[content_start..], first_line, indent),
+        });
+    }
+    blocks
+}
+
+/// A chapter with its valid `katex-macros` blocks replaced.
+#[derive(Clone, Debug)]
+pub struct ReplacedChapter<'a> {
+    /// Content of the chapter as written.
+    pub original: &'a str,
+    /// Content of the chapter with the valid blocks replaced.
+    pub content: Cow<'a, str>,
+    /// Definitions of the valid blocks.
+    pub definitions: Vec<MacroDefinition>,
+    /// Invalid blocks, left as they are.
+    pub invalid: Vec<ChapterMacros>,
+    /// Byte ranges of the valid blocks in the chapter,
+    /// with the length of their replacement in `content`.
+    pub replaced: Vec<(Range<usize>, usize)>,
+}
+
+impl ReplacedChapter<'_> {
+    /// Byte offset in `original` of `offset` in `content`,
+    /// outside the replacements.
+    pub fn original_offset(&self, offset: usize) -> usize {
+        let mut original = offset;
+        for (range, len) in &self.replaced {
+            if range.start > original {
+                break;
+            }
+            original = (original + range.len())
+                .saturating_sub(*len)
+                .max(range.start);
+        }
+        original
+    }
+}
+
+/// Replace each valid `katex-macros` block in `raw_content` with
+/// `replacement(definitions)`, and return the definitions of all valid blocks,
+/// and the invalid blocks, which are left as they are.
+pub fn replace_chapter_macros<F>(raw_content: &str, mut replacement: F) -> ReplacedChapter<'_>
+where
+    F: FnMut(&[MacroDefinition]) -> String,
+{
+    let blocks = find_chapter_macros(raw_content);
+    if blocks.iter().all(|block| block.definitions.is_err()) {
+        return ReplacedChapter {
+            original: raw_content,
+            content: raw_content.into(),
+            definitions: Vec::new(),
+            invalid: blocks,
+            replaced: Vec::new(),
+        };
+    }
+
+    let mut content = String::with_capacity(raw_content.len());
+    let mut definitions = Vec::new();
+    let mut invalid = Vec::new();
+    let mut replaced = Vec::new();
+    let mut checkpoint = 0;
+    for block in blocks {
+        match block.definitions {
+            Ok(block_definitions) => {
+                content.push_str(&raw_content[checkpoint..block.range.start]);
+                checkpoint = block.range.end;
+                let block_replacement = replacement(&block_definitions);
+                content.push_str(&block_replacement);
+                replaced.push((block.range, block_replacement.len()));
+                definitions.extend(block_definitions);
+            }
+            Err(_) => invalid.push(block),
+        }
+    }
+    content.push_str(&raw_content[checkpoint..]);
+    ReplacedChapter {
+        original: raw_content,
+        content: content.into(),
+        definitions,
+        invalid,
+        replaced,
+    }
+}
+
+/// `<script>` tag with the macros in `definitions` as a JSON object,
+/// for client-side rendering to pick up.
+/// Environments are left out, as they are expanded by the preprocessor.
+pub fn chapter_macros_script(definitions: &[MacroDefinition]) -> String {
+    let map: BTreeMap<_, _> = definitions
+        .iter()
+        .filter(|definition| definition.environment().is_none())
+        .map(|definition| (&definition.name, definition.katex_expansion()))
+        .collect();
+    let json = serde_json::to_string(&map)
+        .expect("Serializing strings never fails")
+        .replace('<', r"\u003c");
+    format!("<script type=\"application/json\" class=\"{CHAPTER_MACROS_INFO}\">{json}</script>\n\n")
+}
+
+/// Parse a block `source` whose first line is line `first_line` of the chapter,
+/// and whose fence is indented by `indent` spaces, as its lines may be.
+fn parse_block(
+    source: &str,
+    first_line: usize,
+    indent: usize,
+) -> Result<Vec<MacroDefinition>, MacroErrors> {
+    let offset = |line: usize| line + first_line - 1;
+    let source: Cow<str> = match indent {
+        0 => source.into(),
+        _ => source
+            .split_inclusive('\n')
+            .map(|line| {
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                &line[spaces.min(indent)..]
+            })
+            .collect::<String>()
+            .into(),
+    };
+    parse_macros(&source, Path::new(""))
+        .map(|definitions| {
+            definitions
+                .into_iter()
+                .map(|definition| MacroDefinition {
+                    line: offset(definition.line),
+                    ..definition
+                })
+                .collect()
+        })
+        .map_err(|MacroErrors(errors)| {
+            MacroErrors(
+                errors
+                    .into_iter()
+                    .map(|why| MacroError {
+                        line: offset(why.line),
+                        ..why
+                    })
+                    .collect(),
+            )
+        })
+}
+
+/// Indentation of `line` and the rest of it, trimmed,
+/// if it can be a fence, i.e., it is indented by at most 3 spaces.
+fn fence_line(line: &str) -> Option<(usize, &str)> {
+    let rest = line.trim_start_matches(' ');
+    let indent = line.len() - rest.len();
+    (indent <= 3 && !rest.starts_with('\t')).then(|| (indent, rest.trim_end()))
+}
+
+/// The fence and info string of `line` if it opens a fenced code block.
+fn opening_fence(line: &str) -> Option<(&str, &str)> {
+    let fence_char = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let fence_len = line.len() - line.trim_start_matches(fence_char).len();
+    let (fence, info) = line.split_at(fence_len);
+    (fence_len >= 3).then_some((fence, info.trim()))
+}
+
+/// Whether `line` closes a block opened by `fence`.
+fn is_closing_fence(line: &str, fence: &str) -> bool {
+    let fence_char = fence.as_bytes()[0] as char;
+    line.len() >= fence.len() && line.chars().all(|c| c == fence_char)
+}
@@ -0,0 +1,227 @@
+//! Custom environments, which KaTeX cannot define,
+//! emulated by expanding them in formulas before rendering.
+//!
+//! The environment `name` is defined by a pair of definitions named
+//! `\begin{name}` and `\end{name}`, whose expansions replace
+//! `\begin{name}{arg}...` and `\end{name}` in formulas.
+//! Only `\begin{name}` takes arguments, as in LaTeX.
+use std::fmt;
+
+use super::*;
+
+/// Maximum depth of environments defined in terms of other environments.
+pub const MAX_ENVIRONMENT_DEPTH: usize = 32;
+
+/// A custom environment.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Environment {
+    /// Number of arguments after `\begin{name}`.
+    pub n_args: usize,
+    /// Expansion of `\begin{name}`.
+    pub begin: String,
+    /// Expansion of `\end{name}`.
+    pub end: String,
+}
+
+/// Custom environments by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Environments(pub BTreeMap<String, Environment>);
+
+/// An error expanding custom environments in a formula.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EnvironmentError {
+    /// Byte offset of the error in the formula,
+    /// unknown if it is inside the expansion of an environment.
+    pub offset: Option<usize>,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl fmt::Display for EnvironmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(_) => write!(f, "{}", self.message),
+            None => write!(f, "{}, in the expansion of an environment", self.message),
+        }
+    }
+}
+
+impl MacroDefinition {
+    /// Name of the environment this defines, and whether it defines
+    /// `\begin{name}` rather than `\end{name}`.
+    pub fn environment(&self) -> Option<(&str, bool)> {
+        if let Some(rest) = self.name.strip_prefix(r"\begin{") {
+            return rest.strip_suffix('}').map(|name| (name, true));
+        }
+        let rest = self.name.strip_prefix(r"\end{")?;
+        rest.strip_suffix('}').map(|name| (name, false))
+    }
+}
+
+/// Split `definitions` into macros and environments.
+pub fn split_environments(
+    definitions: Vec<MacroDefinition>,
+) -> (Vec<MacroDefinition>, Environments) {
+    let mut environments = Environments::default();
+    let macros = definitions
+        .into_iter()
+        .filter(|definition| !environments.define(definition))
+        .collect();
+    (macros, environments)
+}
+
+impl Environments {
+    /// Whether there is no custom environment.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Add `definition` if it defines part of an environment,
+    /// and tell whether it does.
+    pub fn define(&mut self, definition: &MacroDefinition) -> bool {
+        let Some((name, is_begin)) = definition.environment() else {
+            return false;
+        };
+        let environment = self.0.entry(name.to_owned()).or_default();
+        match is_begin {
+            true => {
+                environment.n_args = definition
+                    .n_args
+                    .unwrap_or_else(|| max_arg(&definition.expansion));
+                environment.begin = definition.expansion.clone();
+            }
+            false => environment.end = definition.expansion.clone(),
+        }
+        true
+    }
+
+    /// Expand the custom environments in `formula`,
+    /// including those appearing in the expansions.
+    pub fn expand<'a>(&self, formula: &'a str) -> Result<Cow<'a, str>, EnvironmentError> {
+        let mut expanded = Cow::Borrowed(formula);
+        if self.is_empty() {
+            return Ok(expanded);
+        }
+        for depth in 0..MAX_ENVIRONMENT_DEPTH {
+            match self.expand_once(&expanded) {
+                Ok(Some(once)) => expanded = once.into(),
+                Ok(None) => return Ok(expanded),
+                Err(why) => {
+                    return Err(EnvironmentError {
+                        offset: why.offset.filter(|_| depth == 0),
+                        ..why
+                    })
+                }
+            }
+        }
+        Err(EnvironmentError {
+            offset: None,
+            message: format!(
+                "environments nest deeper than {MAX_ENVIRONMENT_DEPTH} levels, is one defined in terms of itself?"
+            ),
+        })
+    }
+
+    /// Expand the custom environments in `formula` once,
+    /// or `None` if there is none.
+    fn expand_once(&self, formula: &str) -> Result<Option<String>, EnvironmentError> {
+        let mut cursor = TexCursor {
+            source: formula,
+            index: 0,
+        };
+        let mut expanded = String::with_capacity(formula.len());
+        let mut checkpoint = 0;
+        // Custom environments not closed yet, and where they begin.
+        let mut open: Vec<(&str, usize)> = Vec::new();
+
+        while let Some(offset) = cursor.rest().find('\\') {
+            cursor.index += offset;
+            let start = cursor.index;
+            let Some(command) = cursor.control_sequence() else {
+                cursor.index += 1;
+                continue;
+            };
+            let is_begin = match command.as_str() {
+                r"\begin" => true,
+                r"\end" => false,
+                _ => continue,
+            };
+            // Malformed `\begin` and `\end` are left for KaTeX to report.
+            let Ok(name) = cursor.group() else {
+                continue;
+            };
+            let Some(environment) = self.0.get(name) else {
+                continue;
+            };
+            let error = |message| EnvironmentError {
+                offset: Some(start),
+                message,
+            };
+
+            let replacement = if is_begin {
+                let mut args = Vec::with_capacity(environment.n_args);
+                for _ in 0..environment.n_args {
+                    let arg = cursor.group().map_err(|_| {
+                        error(format!(
+                            "`\\begin{{{name}}}` expects {} argument(s) in braces",
+                            environment.n_args
+                        ))
+                    })?;
+                    args.push(arg);
+                }
+                open.push((name, start));
+                substitute_args(&environment.begin, &args)
+            } else {
+                match open.pop() {
+                    Some((open_name, _)) if open_name == name => {}
+                    Some((open_name, _)) => {
+                        return Err(error(format!(
+                            "expected `\\end{{{open_name}}}`, found `\\end{{{name}}}`"
+                        )))
+                    }
+                    None => {
+                        return Err(error(format!(
+                            "`\\end{{{name}}}` without `\\begin{{{name}}}` before it"
+                        )))
+                    }
+                }
+                environment.end.clone()
+            };
+            expanded.push_str(&formula[checkpoint..start]);
+            expanded.push_str(&replacement);
+            checkpoint = cursor.index;
+        }
+
+        if let Some((name, start)) = open.pop() {
+            return Err(EnvironmentError {
+                offset: Some(start),
+                message: format!("`\\begin{{{name}}}` is not closed"),
+            });
+        }
+        if checkpoint == 0 {
+            return Ok(None);
+        }
+        expanded.push_str(&formula[checkpoint..]);
+        Ok(Some(expanded))
+    }
+}
+
+/// Replace each `#n` in `expansion` with `args[n - 1]`.
+fn substitute_args(expansion: &str, args: &[&str]) -> String {
+    let mut substituted = String::with_capacity(expansion.len());
+    let mut chars = expansion.chars().peekable();
+    while let Some(c) = chars.next() {
+        let arg = chars
+            .peek()
+            .and_then(|next| next.to_digit(10))
+            .and_then(|n| args.get((n as usize).checked_sub(1)?));
+        match (c, arg) {
+            ('#', Some(arg)) => {
+                substituted.push_str(arg);
+                chars.next();
+            }
+            _ => substituted.push(c),
+        }
+    }
+    substituted
+}
@@ -0,0 +1,36 @@
+//! Parse macros from maps of macro names to expansions,
+//! in `.toml` and `.json` files or inline tables in `book.toml`.
+use super::*;
+
+/// Parse the content `source` of the `.toml` or `.json` macros file at `path`.
+pub fn parse_map_macros(source: &str, path: &Path) -> Result<Vec<MacroDefinition>, MacroErrors> {
+    let error = |message| {
+        MacroErrors(vec![MacroError {
+            path: path.to_owned(),
+            line: 0,
+            message,
+        }])
+    };
+    let map: BTreeMap<String, String> = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(source).map_err(|why| error(why.to_string()))?,
+        _ => toml::from_str(source).map_err(|why| error(why.to_string()))?,
+    };
+    Ok(map_definitions(&map))
+}
+
+/// Definitions in `map` from macro names to expansions.
+/// The backslash in front of macro names is optional.
+pub fn map_definitions(map: &BTreeMap<String, String>) -> Vec<MacroDefinition> {
+    map.iter()
+        .map(|(name, expansion)| MacroDefinition {
+            name: match name.starts_with('\\') {
+                true => name.clone(),
+                false => format!("\\{name}"),
+            },
+            n_args: None,
+            expansion: expansion.clone(),
+            line: 0,
+            description: None,
+        })
+        .collect()
+}
@@ -0,0 +1,48 @@
+//! Macro packages embedded in the binary, emulating common LaTeX packages.
+use super::*;
+
+/// Names and macros files of the embedded macro packages.
+pub const MACRO_PACKAGES: &[(&str, &str)] = &[
+    ("physics", include_str!("packages/physics.txt")),
+    ("braket", include_str!("packages/braket.txt")),
+    ("siunitx-lite", include_str!("packages/siunitx-lite.txt")),
+];
+
+/// Definitions of the embedded macro package `name`, if there is one.
+pub fn macro_package(name: &str) -> Option<Vec<MacroDefinition>> {
+    let (_, source) = MACRO_PACKAGES
+        .iter()
+        .find(|(package, _)| *package == name)?;
+    let path = PathBuf::from(format!("<{name}>"));
+    Some(parse_macros(source, &path).expect("Embedded macro packages are valid"))
+}
+
+/// Fail if any of `packages` is unknown.
+pub fn check_macro_packages(packages: &[String]) -> Result<()> {
+    let known = |name: &String| MACRO_PACKAGES.iter().any(|(package, _)| package == name);
+    let Some(name) = packages.iter().find(|name| !known(name)) else {
+        return Ok(());
+    };
+    let known: Vec<_> = MACRO_PACKAGES.iter().map(|(package, _)| *package).collect();
+    Err(mdbook_preprocessor::errors::Error::msg(format!(
+        "[preprocessor.katex]: `{name}` is not a valid choice for `macro-packages`! Please check your `book.toml`. Valid choices are `{}`.",
+        known.join("`, `")
+    )))
+}
+
+/// Definitions of the macro `packages`, in order.
+/// Definitions from later packages override earlier ones with the same name.
+/// Fail if any package is unknown.
+pub fn load_macro_packages(packages: &[String]) -> Result<Vec<MacroDefinition>> {
+    check_macro_packages(packages)?;
+    let mut definitions: Vec<MacroDefinition> = Vec::new();
+    for loaded in packages.iter().filter_map(|name| macro_package(name)) {
+        for definition in loaded {
+            match definitions.iter_mut().find(|d| d.name == definition.name) {
+                Some(existing) => *existing = definition,
+                None => definitions.push(definition),
+            }
+        }
+    }
+    Ok(definitions)
+}
@@ -0,0 +1,354 @@
+//! Parse macros from LaTeX preambles and formulas.
+//!
+//! Supported are `\newcommand`, `\renewcommand`, `\providecommand`,
+//! `\def`, `\gdef`, `\DeclareMathOperator`, `\newenvironment`
+//! and `\renewenvironment`, starred or not.
+//! Everything else in the preamble is ignored.
+use super::*;
+
+/// Parse the content `source` of the LaTeX preamble at `path`.
+pub fn parse_tex_macros(source: &str, path: &Path) -> Result<Vec<MacroDefinition>, MacroErrors> {
+    // Strip comments but keep line breaks, so byte offsets map to lines.
+    let (lines, comments): (Vec<_>, Vec<_>) = source.lines().map(strip_comment).unzip();
+    let stripped = lines.join("\n");
+    let mut cursor = TexCursor {
+        source: &stripped,
+        index: 0,
+    };
+    let mut definitions: Vec<MacroDefinition> = Vec::new();
+    let mut errors = Vec::new();
+
+    while let Some(offset) = cursor.rest().find('\\') {
+        cursor.index += offset;
+        let start = cursor.index;
+        let line = cursor.line_at(start);
+        let Some(command) = cursor.control_sequence() else {
+            cursor.index += 1;
+            continue;
+        };
+        let parsed = match command.as_str() {
+            r"\newcommand" | r"\renewcommand" | r"\providecommand" => {
+                cursor.parse_newcommand().and_then(|(name, n_args, expansion)| {
+                    let existing = definitions.iter().find(|d| d.name == name);
+                    match (command.as_str(), existing) {
+                        (r"\newcommand", Some(existing)) => Err(format!(
+                            "`{name}` is already defined at line {}, use `\\renewcommand` to redefine it",
+                            existing.line
+                        )),
+                        (r"\providecommand", Some(_)) => Ok(vec![]),
+                        _ => Ok(vec![(name, n_args, expansion)]),
+                    }
+                })
+            }
+            r"\newenvironment" | r"\renewenvironment" => {
+                cursor.parse_newenvironment().and_then(|(begin, end)| {
+                    let existing = definitions.iter().find(|d| d.name == begin.0);
+                    match (command.as_str(), existing) {
+                        (r"\newenvironment", Some(existing)) => Err(format!(
+                            "`{}` is already defined at line {}, use `\\renewenvironment` to redefine it",
+                            begin.0, existing.line
+                        )),
+                        _ => Ok(vec![begin, end]),
+                    }
+                })
+            }
+            r"\def" | r"\gdef" => cursor.parse_def().map(|parsed| vec![parsed]),
+            r"\DeclareMathOperator" => cursor.parse_operator().map(|parsed| vec![parsed]),
+            _ => continue,
+        };
+        match parsed {
+            Ok(parsed) => {
+                // The comment at the end of the definition describes it.
+                let end_line = cursor.line_at(cursor.index);
+                let comment = comments.get(end_line - 1).copied().flatten();
+                for (name, n_args, expansion) in parsed {
+                    let definition = MacroDefinition {
+                        name,
+                        n_args,
+                        expansion,
+                        line,
+                        description: description(comment),
+                    };
+                    match definitions.iter_mut().find(|d| d.name == definition.name) {
+                        Some(existing) => *existing = definition,
+                        None => definitions.push(definition),
+                    }
+                }
+            }
+            Err(message) => {
+                errors.push(MacroError {
+                    path: path.to_owned(),
+                    line,
+                    message: format!("in `{command}`: {message}"),
+                });
+                // Skip the command name to carry on after the error.
+                cursor.index = start + command.len();
+            }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(definitions),
+        false => Err(MacroErrors(errors)),
+    }
+}
+
+/// Global definitions in formula `source`, i.e., `\gdef`, `\xdef`, and
+/// `\def`, `\edef` or `\newcommand` and alike after `\global`, in order.
+/// Invalid definitions are skipped, for KaTeX to report them when rendering.
+pub fn parse_global_macros(source: &str) -> Vec<MacroDefinition> {
+    let mut cursor = TexCursor { source, index: 0 };
+    let mut definitions: Vec<MacroDefinition> = Vec::new();
+    let mut global = false;
+
+    while let Some(offset) = cursor.rest().find('\\') {
+        cursor.index += offset;
+        let line = cursor.line_at(cursor.index);
+        let Some(command) = cursor.control_sequence() else {
+            cursor.index += 1;
+            continue;
+        };
+        let parsed = match command.as_str() {
+            r"\global" => {
+                global = true;
+                continue;
+            }
+            r"\gdef" | r"\xdef" => cursor.parse_def(),
+            r"\def" | r"\edef" if global => cursor.parse_def(),
+            r"\newcommand" | r"\renewcommand" | r"\providecommand" if global => {
+                cursor.parse_newcommand()
+            }
+            _ => {
+                global = false;
+                continue;
+            }
+        };
+        global = false;
+        if let Ok((name, n_args, expansion)) = parsed {
+            let definition = MacroDefinition {
+                name,
+                n_args,
+                expansion,
+                line,
+                description: None,
+            };
+            match definitions.iter_mut().find(|d| d.name == definition.name) {
+                Some(existing) => *existing = definition,
+                None => definitions.push(definition),
+            }
+        }
+    }
+    definitions
+}
+
+/// Control sequences used in `source`, with `\begin{name}` and `\end{name}`
+/// for environments.
+pub fn macro_uses(source: &str) -> BTreeSet<String> {
+    let mut cursor = TexCursor { source, index: 0 };
+    let mut uses = BTreeSet::new();
+    while let Some(offset) = cursor.rest().find('\\') {
+        cursor.index += offset;
+        let Some(command) = cursor.control_sequence() else {
+            cursor.index += 1;
+            continue;
+        };
+        match command.as_str() {
+            r"\begin" | r"\end" => {
+                if let Ok(name) = cursor.group() {
+                    uses.insert(format!("{command}{{{name}}}"));
+                }
+            }
+            _ => {
+                uses.insert(command);
+            }
+        }
+    }
+    uses
+}
+
+/// A parsed definition: name, explicit number of arguments, and expansion.
+type Parsed = (String, Option<usize>, String);
+
+/// Cursor over a comment-free LaTeX source.
+pub(super) struct TexCursor<'a> {
+    pub(super) source: &'a str,
+    pub(super) index: usize,
+}
+
+impl<'a> TexCursor<'a> {
+    /// The source after the cursor.
+    pub(super) fn rest(&self) -> &'a str {
+        &self.source[self.index..]
+    }
+
+    /// Line number of byte `index`.
+    fn line_at(&self, index: usize) -> usize {
+        self.source[..index].matches('\n').count() + 1
+    }
+
+    /// Skip whitespace.
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.index += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consume `c` after whitespace if it is next.
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        let eaten = self.rest().starts_with(c);
+        if eaten {
+            self.index += c.len_utf8();
+        }
+        eaten
+    }
+
+    /// Consume a control sequence such as `\foo` or `\,`.
+    pub(super) fn control_sequence(&mut self) -> Option<String> {
+        let rest = self.rest().strip_prefix('\\')?;
+        let len = match rest.find(|c: char| !(c.is_ascii_alphabetic() || c == '@')) {
+            Some(0) => rest.chars().next()?.len_utf8(),
+            Some(len) => len,
+            None => rest.len(),
+        };
+        if len == 0 {
+            return None;
+        }
+        self.index += 1 + len;
+        Some(format!("\\{}", &rest[..len]))
+    }
+
+    /// Consume a `{...}` group with balanced braces, and return its content.
+    pub(super) fn group(&mut self) -> Result<&'a str, String> {
+        if !self.eat('{') {
+            return Err("expected `{`".into());
+        }
+        let start = self.index;
+        let mut depth = 1;
+        let mut escaped = false;
+        for (offset, c) in self.rest().char_indices() {
+            match c {
+                '\\' => {
+                    escaped = !escaped;
+                    continue;
+                }
+                '{' if !escaped => depth += 1,
+                '}' if !escaped => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.index = start + offset + 1;
+                        return Ok(self.source[start..start + offset].trim());
+                    }
+                }
+                _ => {}
+            }
+            escaped = false;
+        }
+        Err("unbalanced braces".into())
+    }
+
+    /// Consume a `[...]` option, if any.
+    fn option(&mut self) -> Result<Option<&'a str>, String> {
+        if !self.eat('[') {
+            return Ok(None);
+        }
+        let start = self.index;
+        let Some(len) = self.rest().find(']') else {
+            return Err("unclosed `[`".into());
+        };
+        self.index += len + 1;
+        Ok(Some(self.source[start..start + len].trim()))
+    }
+
+    /// Consume the name of the macro being defined, as `\name` or `{\name}`.
+    fn macro_name(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        if self.rest().starts_with('{') {
+            let group = self.group()?;
+            let mut inner = TexCursor {
+                source: group,
+                index: 0,
+            };
+            match inner.control_sequence() {
+                Some(name) if inner.rest().trim().is_empty() => Ok(name),
+                _ => Err(format!("expected a macro name, found `{group}`")),
+            }
+        } else {
+            self.control_sequence()
+                .ok_or_else(|| "expected a macro name".into())
+        }
+    }
+
+    /// Consume the `[n_args]` option of `name`, if any,
+    /// and reject a default value for an optional argument.
+    fn arg_count(&mut self, name: &str) -> Result<Option<usize>, String> {
+        let n_args = match self.option()? {
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) if count <= 9 => Some(count),
+                _ => return Err(format!("invalid argument count `{count}` for `{name}`")),
+            },
+            None => None,
+        };
+        if self.option()?.is_some() {
+            return Err(format!(
+                "optional arguments are not supported by KaTeX, in `{name}`"
+            ));
+        }
+        Ok(n_args)
+    }
+
+    /// Parse `{\name}[n_args]{expansion}` after `\newcommand` and alike.
+    fn parse_newcommand(&mut self) -> Result<Parsed, String> {
+        self.eat('*');
+        let name = self.macro_name()?;
+        let n_args = self.arg_count(&name)?;
+        let expansion = self.group()?.to_owned();
+        Ok((name, n_args, expansion))
+    }
+
+    /// Parse `\name#1#2{expansion}` after `\def`.
+    fn parse_def(&mut self) -> Result<Parsed, String> {
+        self.skip_whitespace();
+        let name = self
+            .control_sequence()
+            .ok_or_else(|| "expected a macro name".to_owned())?;
+        let Some(len) = self.rest().find('{') else {
+            return Err(format!("expected `{{` in the definition of `{name}`"));
+        };
+        let parameters = &self.rest()[..len];
+        let n_args = max_arg(parameters);
+        if parameters.trim() != (1..=n_args).map(|n| format!("#{n}")).collect::<String>() {
+            return Err(format!(
+                "delimited parameters `{}` are not supported, in `{name}`",
+                parameters.trim()
+            ));
+        }
+        self.index += len;
+        let expansion = self.group()?.to_owned();
+        Ok((name, Some(n_args), expansion))
+    }
+
+    /// Parse `{name}[n_args]{begin}{end}` after `\newenvironment` and alike,
+    /// into the definitions of `\begin{name}` and `\end{name}`.
+    fn parse_newenvironment(&mut self) -> Result<(Parsed, Parsed), String> {
+        self.eat('*');
+        let name = self.group()?;
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '*') {
+            return Err(format!("invalid environment name `{name}`"));
+        }
+        let n_args = self.arg_count(name)?;
+        let begin = self.group()?.to_owned();
+        let end = self.group()?.to_owned();
+        Ok((
+            (format!("\\begin{{{name}}}"), n_args, begin),
+            (format!("\\end{{{name}}}"), None, end),
+        ))
+    }
+
+    /// Parse `{\name}{text}` after `\DeclareMathOperator`.
+    fn parse_operator(&mut self) -> Result<Parsed, String> {
+        let star = if self.eat('*') { "*" } else { "" };
+        let name = self.macro_name()?;
+        let text = self.group()?;
+        Ok((name, None, format!(r"\operatorname{star}{{{text}}}")))
+    }
+}
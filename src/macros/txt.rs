@@ -0,0 +1,181 @@
+//! Parse macros files in the `.txt` format.
+//!
+//! Each definition is a macro name, an optional argument count, a `:`,
+//! and the expansion, e.g., `\R[2]: \mathbb{R}^{#1 \times #2}`.
+//! Environments are defined by `\begin{name}` and `\end{name}` alike.
+//! `%` starts a comment, which describes the definition if it follows it,
+//! blank lines are ignored, and indented lines continue the previous definition.
+//! Other lines not starting with `\` are ignored with a warning.
+use super::*;
+
+/// Parse the content `source` of the macros file at `path`.
+pub fn parse_macros(source: &str, path: &Path) -> Result<Vec<MacroDefinition>, MacroErrors> {
+    let mut definitions: Vec<MacroDefinition> = Vec::new();
+    let mut errors = Vec::new();
+    let mut error = |line, message| {
+        errors.push(MacroError {
+            path: path.to_owned(),
+            line,
+            message,
+        })
+    };
+    // Whether the previous line ended in a comment, which eats the newline.
+    let mut commented = false;
+
+    for (index, raw_line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let (line, comment) = strip_comment(raw_line);
+        let previous_commented = std::mem::replace(&mut commented, comment.is_some());
+        let line = line.trim_end();
+        let continued = raw_line.starts_with(char::is_whitespace);
+        if line.trim_start().is_empty() {
+            // Indented comments continue the description.
+            if let (true, Some(definition)) = (continued, definitions.last_mut()) {
+                describe_more(definition, comment);
+            }
+            continue;
+        }
+
+        if continued {
+            match definitions.last_mut() {
+                Some(definition) => {
+                    if !previous_commented {
+                        definition.expansion.push(' ');
+                    }
+                    definition.expansion.push_str(line.trim_start());
+                    describe_more(definition, comment);
+                }
+                None => error(
+                    line_number,
+                    "continuation line without a macro definition before it".into(),
+                ),
+            }
+            continue;
+        }
+
+        // Such lines used to be ignored, so only warn about them.
+        if !line.starts_with('\\') {
+            warn!(
+                "{}:{line_number}: ignoring `{line}`, which is not a macro definition starting with `\\`.",
+                path.display()
+            );
+            continue;
+        }
+
+        match parse_definition(line, line_number) {
+            Ok(mut definition) => {
+                definition.description = description(comment);
+                if let Some(first) = definitions.iter().find(|d| d.name == definition.name) {
+                    error(
+                        line_number,
+                        format!(
+                            "duplicate definition of `{}`, first defined at line {}",
+                            definition.name, first.line
+                        ),
+                    );
+                }
+                definitions.push(definition);
+            }
+            Err(message) => error(line_number, message),
+        }
+    }
+
+    for definition in &definitions {
+        if let Some((environment, false)) = definition.environment() {
+            if definition.n_args.is_some() || max_arg(&definition.expansion) > 0 {
+                error(
+                    definition.line,
+                    format!(
+                        "`{}` cannot take arguments, only `\\begin{{{environment}}}` can",
+                        definition.name
+                    ),
+                );
+            }
+        }
+        if let Some(n_args) = definition.n_args {
+            let used = max_arg(&definition.expansion);
+            if used > n_args {
+                error(
+                    definition.line,
+                    format!(
+                        "`{}` declares {n_args} argument(s) but uses `#{used}`",
+                        definition.name
+                    ),
+                );
+            }
+        }
+    }
+
+    errors.sort_by_key(|why| why.line);
+    match errors.is_empty() {
+        true => Ok(definitions),
+        false => Err(MacroErrors(errors)),
+    }
+}
+
+/// Append `comment` to the description of `definition`.
+fn describe_more(definition: &mut MacroDefinition, comment: Option<&str>) {
+    if let Some(more) = description(comment) {
+        definition.description = Some(match definition.description.take() {
+            Some(described) => format!("{described} {more}"),
+            None => more,
+        });
+    }
+}
+
+/// Parse a single-line definition `\name[n_args]: expansion`.
+fn parse_definition(line: &str, line_number: usize) -> Result<MacroDefinition, String> {
+    let Some(rest) = line.strip_prefix('\\') else {
+        return Err(format!(
+            "expected a macro definition starting with `\\`, found `{line}`"
+        ));
+    };
+    let name_len = match rest.find(|c: char| !(c.is_ascii_alphabetic() || c == '@')) {
+        Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+        Some(len) => len,
+        None => rest.len(),
+    };
+    if name_len == 0 {
+        return Err("expected a macro name after `\\`".into());
+    }
+    let mut name = format!("\\{}", &rest[..name_len]);
+    let mut rest = &rest[name_len..];
+    if name == r"\begin" || name == r"\end" {
+        let Some((environment, after)) = rest.strip_prefix('{').and_then(|r| r.split_once('}'))
+        else {
+            return Err(format!(
+                "expected an environment name in braces after `{name}`"
+            ));
+        };
+        name = format!("{name}{{{environment}}}");
+        rest = after;
+    }
+    let mut rest = rest.trim_start();
+
+    let mut n_args = None;
+    if let Some(after_bracket) = rest.strip_prefix('[') {
+        let Some((count, after)) = after_bracket.split_once(']') else {
+            return Err(format!("unclosed `[` after `{name}`"));
+        };
+        match count.trim().parse::<usize>() {
+            Ok(count) if count <= 9 => n_args = Some(count),
+            _ => {
+                return Err(format!(
+                    "the argument count of `{name}` must be a number from 0 to 9, found `{count}`"
+                ))
+            }
+        }
+        rest = after.trim_start();
+    }
+
+    let Some(expansion) = rest.strip_prefix(':') else {
+        return Err(format!("expected `:` after `{name}`"));
+    };
+    Ok(MacroDefinition {
+        name,
+        n_args,
+        expansion: expansion.trim().into(),
+        line: line_number,
+        description: None,
+    })
+}
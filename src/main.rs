@@ -1,8 +1,16 @@
-use clap::{crate_version, Arg, ArgMatches, Command};
-use mdbook_katex::{init_tracing, preprocess::KatexProcessor};
+use clap::{crate_version, Arg, ArgAction, ArgMatches, Command};
+use mdbook_katex::{
+    assets::write_if_changed,
+    cache::clear_cache,
+    cfg::{config_schema, get_book_config},
+    init_tracing,
+    preprocess::KatexProcessor,
+    rename::rename_macro,
+    report::book_macro_report,
+};
 use mdbook_preprocessor::errors::{Error, Result};
 use mdbook_preprocessor::{parse_input, Preprocessor};
-use std::io;
+use std::{io, path::Path};
 use tracing::*;
 
 /// Parse CLI options.
@@ -10,11 +18,58 @@ pub fn make_app() -> Command {
     Command::new("mdbook-katex")
         .version(crate_version!())
         .about("A preprocessor that renders KaTex equations to HTML.")
+        .arg(
+            Arg::new("clear-cache")
+                .long("clear-cache")
+                .value_name("BOOK_DIR")
+                .num_args(0..=1)
+                .default_missing_value(".")
+                .help("Remove the render cache of the book in BOOK_DIR, `.` by default, and exit"),
+        )
         .subcommand(
             Command::new("supports")
                 .arg(Arg::new("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            Command::new("schema")
+                .about("Print the JSON Schema of the `[preprocessor.katex]` configuration"),
+        )
+        .subcommand(
+            Command::new("macros")
+                .arg(
+                    Arg::new("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Report unused macros and undefined control sequences in a book"),
+        )
+        .subcommand(
+            Command::new("rename-macro")
+                .arg(
+                    Arg::new("old")
+                        .required(true)
+                        .help("Macro to rename, e.g., `\\R`"),
+                )
+                .arg(
+                    Arg::new("new")
+                        .required(true)
+                        .help("New name, e.g., `\\Reals`"),
+                )
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Print a unified diff instead of rewriting the files"),
+                )
+                .about("Rename a macro in the math of a book and in its macros files"),
+        )
 }
 
 /// Produce a warning on mdBook version mismatch.
@@ -44,6 +99,52 @@ fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> Result<()>
     }
 }
 
+/// Print the JSON Schema of the configuration.
+fn handle_schema() -> Result<()> {
+    serde_json::to_writer_pretty(io::stdout(), &config_schema())?;
+    println!();
+    Ok(())
+}
+
+/// Print the macro report of the book in the given directory.
+fn handle_macros(sub_args: &ArgMatches) -> Result<()> {
+    let dir = sub_args
+        .get_one::<String>("dir")
+        .expect("Argument with default value");
+    print!("{}", book_macro_report(Path::new(dir))?);
+    Ok(())
+}
+
+/// Rename a macro in the book in the given directory.
+fn handle_rename_macro(sub_args: &ArgMatches) -> Result<()> {
+    let get = |name| {
+        sub_args
+            .get_one::<String>(name)
+            .expect("Required argument or default value")
+    };
+    let root = Path::new(get("dir"));
+    let rewrites = rename_macro(root, get("old"), get("new"))?;
+    for rewrite in &rewrites {
+        if sub_args.get_flag("dry-run") {
+            print!("{}", rewrite.unified_diff(root));
+        } else {
+            write_if_changed(&rewrite.path, rewrite.after.as_bytes())?;
+            println!("Rewrote {}", rewrite.path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Remove the render cache of the book in `dir`.
+fn handle_clear_cache(dir: &str) -> Result<()> {
+    let root = Path::new(dir);
+    let (cfg, _) = get_book_config(root)?;
+    let cache_dir = cfg.cache_dir(root);
+    clear_cache(&cache_dir)?;
+    println!("Removed {}", cache_dir.display());
+    Ok(())
+}
+
 /// Preprocess `book` using `pre` and print it out.
 fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<()> {
     let (ctx, book) = parse_input(io::stdin())?;
@@ -62,9 +163,17 @@ fn main() -> Result<()> {
     let pre = KatexProcessor;
 
     // determine what behaviour has been requested
-    if let Some(sub_args) = matches.subcommand_matches("supports") {
+    if let Some(dir) = matches.get_one::<String>("clear-cache") {
+        handle_clear_cache(dir)
+    } else if let Some(sub_args) = matches.subcommand_matches("supports") {
         // handle cmdline supports
         handle_supports(&pre, sub_args)
+    } else if matches.subcommand_matches("schema").is_some() {
+        handle_schema()
+    } else if let Some(sub_args) = matches.subcommand_matches("macros") {
+        handle_macros(sub_args)
+    } else if let Some(sub_args) = matches.subcommand_matches("rename-macro") {
+        handle_rename_macro(sub_args)
     } else {
         // handle preprocessing
         handle_preprocessing(&pre)
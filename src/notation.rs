@@ -0,0 +1,126 @@
+//! Generated chapter listing the notation defined by the book macros.
+use mdbook_preprocessor::book::{BookItem, Chapter};
+
+use super::*;
+
+/// Path of the generated notation chapter, relative to the book source.
+pub const NOTATION_CHAPTER: &str = "notation.md";
+
+/// Append to `book` the chapter titled `title` listing `definitions`,
+/// unless a chapter already lives at `NOTATION_CHAPTER`.
+pub fn add_notation_chapter(
+    book: &mut Book,
+    title: &str,
+    definitions: &[MacroDefinition],
+    inline_delimiter: &Delimiter,
+) {
+    let taken = book.iter().any(|item| match item {
+        BookItem::Chapter(chapter) => chapter.path.as_deref() == Some(Path::new(NOTATION_CHAPTER)),
+        _ => false,
+    });
+    if taken {
+        error!(
+            "[preprocessor.katex]: Not generating the notation chapter, because `{NOTATION_CHAPTER}` is already a chapter of the book."
+        );
+        return;
+    }
+    book.push_item(Chapter {
+        name: title.into(),
+        content: notation_content(title, definitions, inline_delimiter),
+        path: Some(NOTATION_CHAPTER.into()),
+        ..Default::default()
+    });
+}
+
+/// Whether the chapter at `path` is the notation chapter generated with `cfg`,
+/// whose formulas are in the cells of a table.
+pub fn is_notation_chapter(cfg: &KatexConfig, path: Option<&Path>) -> bool {
+    cfg.notation_chapter.is_some() && path == Some(Path::new(NOTATION_CHAPTER))
+}
+
+/// Processed `formula` of a table cell, with its `|` escaped as a character
+/// reference so that they do not end the cell, either in rendered HTML
+/// or in escaped math, which katex.js reads once the reference is decoded.
+pub fn escape_table_pipes(formula: &str) -> Cow<'_, str> {
+    match formula.contains('|') {
+        true => formula.replace('|', "&#124;").into(),
+        false => formula.into(),
+    }
+}
+
+/// Markdown content of the notation chapter titled `title`, with a table of
+/// `definitions` showing their rendering, usage and description.
+/// Formulas use `inline_delimiter`, to be rendered with the other chapters.
+pub fn notation_content(
+    title: &str,
+    definitions: &[MacroDefinition],
+    inline_delimiter: &Delimiter,
+) -> String {
+    let mut content = format!("# {title}\n\n");
+    let rows: Vec<_> = definitions
+        .iter()
+        .filter_map(|definition| notation_row(definition, inline_delimiter))
+        .collect();
+    if rows.is_empty() {
+        content.push_str("No macros are defined.\n");
+        return content;
+    }
+    content.push_str("| Notation | Command | Description |\n| --- | --- | --- |\n");
+    for row in rows {
+        content.push_str(&row);
+    }
+    content
+}
+
+/// Row of the notation table for `definition`,
+/// or `None` for the end of an environment, listed with its beginning.
+fn notation_row(definition: &MacroDefinition, inline_delimiter: &Delimiter) -> Option<String> {
+    let n_args = definition
+        .n_args
+        .unwrap_or_else(|| max_arg(&definition.expansion));
+    let args = |hash: &str| {
+        (1..=n_args)
+            .map(|n| format!("{{{hash}{n}}}"))
+            .collect::<String>()
+    };
+    let (usage, formula) = match definition.environment() {
+        Some((_, false)) => return None,
+        Some((name, true)) => (
+            format!("{}{} ... \\end{{{name}}}", definition.name, args("#")),
+            format!("{}{} \\cdots \\end{{{name}}}", definition.name, args(r"\#")),
+        ),
+        None => (
+            format!("{}{}", definition.name, args("#")),
+            format!("{}{}", definition.name, args(r"\#")),
+        ),
+    };
+    let usage = usage.replace('|', r"\|");
+    let description = escape_description(
+        definition.description.as_deref().unwrap_or_default(),
+        inline_delimiter,
+    );
+    let Delimiter { left, right } = inline_delimiter;
+    Some(format!(
+        "| {left}{formula}{right} | `{usage}` | {description} |\n"
+    ))
+}
+
+/// `description` with `|` escaped outside its formulas between
+/// `inline_delimiter`, which are escaped once processed.
+fn escape_description(description: &str, inline_delimiter: &Delimiter) -> String {
+    let Delimiter { left, right } = inline_delimiter;
+    let mut escaped = String::with_capacity(description.len());
+    let mut rest = description;
+    while let Some(start) = rest.find(left.as_str()) {
+        let after_left = start + left.len();
+        let Some(len) = rest[after_left..].find(right.as_str()) else {
+            break;
+        };
+        let end = after_left + len + right.len();
+        escaped.push_str(&rest[..start].replace('|', r"\|"));
+        escaped.push_str(&rest[start..end]);
+        rest = &rest[end..];
+    }
+    escaped.push_str(&rest.replace('|', r"\|"));
+    escaped
+}
@@ -1,4 +1,6 @@
 //! Preprocessing and escaping with KaTeX.
+use std::time::Duration;
+
 use super::*;
 
 /// When `pre-render` is called but not enabled.
@@ -6,26 +8,162 @@ use super::*;
 pub fn process_all_chapters_prerender(
     _: &mut Book,
     _: &KatexConfig,
-    _: &str,
+    _: &Stylesheet,
     _: &PreprocessorContext,
-) -> Vec<String> {
+) -> Result<Vec<RenderFailure>> {
     panic!("Pre-render is unavailable because this `mdbook-katex` program does not have the `pre-render` feature enabled, only escaping mode is available, and you can set `pre-render = false` to enable it. If you do need `pre-render` mode, you need to add the `pre-render` feature and recompile. See the README at <https://github.com/lzanini/mdbook-katex/blob/master/README.md>.")
 }
 
+/// CDN URL of the KaTeX stylesheet, as a literal for `concat!`.
+/// Its version is that of the KaTeX used for pre-rendering.
+macro_rules! katex_cdn_stylesheet {
+    () => {
+        "https://cdn.jsdelivr.net/npm/katex@0.16.4/dist/katex.min.css"
+    };
+}
+
+/// CDN URL of the KaTeX stylesheet, as linked by `KATEX_HEADER`.
+pub const KATEX_CDN_STYLESHEET: &str = katex_cdn_stylesheet!();
+
 /// Header that points to CDN for the KaTeX stylesheet.
-pub const KATEX_HEADER: &str = r#"<link rel="stylesheet" href="https://cdn.jsdelivr.net/npm/katex@0.16.4/dist/katex.min.css">
+pub const KATEX_HEADER: &str = concat!(
+    r#"<link rel="stylesheet" href=""#,
+    katex_cdn_stylesheet!(),
+    "\">\n\n"
+);
+
+/// How math is processed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Render math with KaTeX in the preprocessor.
+    PreRender,
+    /// Escape math for client-side rendering.
+    Escape,
+    /// Render math with KaTeX in the preprocessor,
+    /// and escape the formulas that fail for client-side rendering.
+    Hybrid,
+}
+
+/// Source of the KaTeX stylesheet linked from each chapter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stylesheet {
+    /// Do not link any stylesheet.
+    None,
+    /// Link the stylesheet on the CDN.
+    Cdn,
+    /// Link the stylesheet embedded in the binary and written into the book.
+    Embedded,
+}
+
+/// Stylesheet file linking the KaTeX stylesheet once for the whole book,
+/// written to the book root for `inject-css = "once"`.
+pub const ONCE_STYLESHEET: &str = "katex.css";
+
+/// Where the KaTeX stylesheet is injected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CssInjection {
+    /// At the top of every chapter.
+    Chapter,
+    /// At the top of chapters that contain math.
+    Math,
+    /// Once for the whole book, through `output.html.additional-css`.
+    Once,
+    /// Nowhere.
+    Never,
+}
+
+impl Stylesheet {
+    /// Header to prepend to the chapter at `chapter_path` with `raw_content`,
+    /// following `injection`.
+    pub fn chapter_header(
+        &self,
+        injection: CssInjection,
+        chapter_path: Option<&Path>,
+        raw_content: &str,
+        extra_opts: &ExtraOpts,
+    ) -> Cow<'static, str> {
+        match injection {
+            CssInjection::Chapter => self.header(chapter_path),
+            CssInjection::Math if contains_math(raw_content, extra_opts) => {
+                self.header(chapter_path)
+            }
+            CssInjection::Math | CssInjection::Once | CssInjection::Never => "".into(),
+        }
+    }
+
+    /// Content of `ONCE_STYLESHEET`, importing the KaTeX stylesheet.
+    pub fn import_css(&self) -> Option<String> {
+        let href = match self {
+            Stylesheet::None => return None,
+            Stylesheet::Cdn => KATEX_CDN_STYLESHEET.to_owned(),
+            Stylesheet::Embedded => format!("{ASSETS_DIR}/{ASSETS_STYLESHEET}"),
+        };
+        Some(format!("@import url(\"{href}\");\n"))
+    }
+
+    /// Header to prepend to the chapter at `chapter_path`.
+    pub fn header(&self, chapter_path: Option<&Path>) -> Cow<'static, str> {
+        match self {
+            Stylesheet::None => "".into(),
+            Stylesheet::Cdn => KATEX_HEADER.into(),
+            Stylesheet::Embedded => embedded_stylesheet_link(chapter_path).into(),
+        }
+    }
+}
+
+/// Inclusion of math source in rendered HTML.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SourceInclusion {
+    /// Do not include it.
+    None,
+    /// In the `value` of a `data.katex-src` tag wrapping the math.
+    Data,
+    /// In a MathML `<annotation encoding="application/x-tex">`,
+    /// as KaTeX's copy-tex extension expects.
+    Annotation,
+    /// In a `data-latex` attribute of the `span.katex`.
+    Attribute,
+}
 
-"#;
+/// Presentation of formulas that fail to render.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ErrorDisplay {
+    /// The formula between its delimiters, as written.
+    Raw,
+    /// A `span.katex-error` showing the formula in monospace and `color`,
+    /// with the error message as its title.
+    Span {
+        /// CSS color of the formula.
+        color: String,
+    },
+    /// HTML with `{source}` and `{message}` replaced.
+    Template(String),
+    /// Escaped as in escape mode, in a `FALLBACK_CLASS` span
+    /// for client-side rendering, in hybrid mode.
+    Fallback,
+}
 
 /// Extra options for the KaTeX preprocessor.
 #[derive(Clone, Debug)]
 pub struct ExtraOpts {
-    /// Path to macro file.
-    pub include_src: bool,
+    /// How to include math source in rendered HTML.
+    pub include_src: SourceInclusion,
+    /// Carry global macro definitions over to the following formulas.
+    pub persist_macros: bool,
     /// Delimiter for math display block.
     pub block_delimiter: Delimiter,
     /// Delimiter for math inline block.
     pub inline_delimiter: Delimiter,
+    /// How formulas that fail to render are shown.
+    pub error_display: ErrorDisplay,
+    /// Maximum length of a formula in bytes.
+    pub max_formula_length: Option<usize>,
+    /// Time limit to render a formula.
+    pub render_timeout: Option<Duration>,
+    /// Base URL of the KaTeX distribution rendering fallback formulas.
+    pub fallback_url: String,
+    /// Cache of rendered formulas, with `cache = true` in pre-render mode.
+    pub cache: Option<RenderCache>,
 }
 
 /// KaTeX `mdbook::preprocess::Proprecessor` for mdBook.
@@ -39,52 +177,215 @@ impl Preprocessor for KatexProcessor {
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book> {
         // parse TOML config
-        let cfg = get_config(&ctx.config)?;
-        let header = if cfg.no_css { "" } else { KATEX_HEADER }.to_owned();
+        let cfg = get_context_config(ctx)?;
+        let stylesheet = cfg.stylesheet();
+        if stylesheet == Stylesheet::Embedded {
+            write_embedded_assets(&ctx.root.join(&ctx.config.book.src).join(ASSETS_DIR))?;
+        }
+        if cfg.css_injection() == CssInjection::Once {
+            write_once_stylesheet(&stylesheet, ctx)?;
+        }
+        // Report on the chapters as written, once rendered.
+        let report_chapters = match (cfg.report_macros, cfg.mode()) {
+            (true, Mode::Escape) => {
+                warn!("[preprocessor.katex]: `report-macros` is ignored in escape mode, as nothing is rendered.");
+                None
+            }
+            (true, _) => Some(report_chapters(&book)),
+            (false, _) => None,
+        };
+        if let Some(title) = &cfg.notation_chapter {
+            let definitions = load_macro_definitions(&ctx.root, &cfg.macros)?;
+            add_notation_chapter(&mut book, title, &definitions, &cfg.inline_delimiter);
+        }
 
-        if cfg.pre_render {
-            process_all_chapters_prerender(&mut book, &cfg, &header, ctx);
-        } else {
-            process_all_chapters_escape(&mut book, &cfg, &header, ctx);
+        match cfg.mode() {
+            Mode::PreRender | Mode::Hybrid => {
+                let failures = process_all_chapters_prerender(&mut book, &cfg, &stylesheet, ctx)?;
+                if let Some(chapters) = report_chapters {
+                    warn_macro_report(&chapters, &cfg, &ctx.root, &failures)?;
+                }
+                report_failures(&failures, cfg.fail_on_error)?;
+            }
+            Mode::Escape => process_all_chapters_escape(&mut book, &cfg, &stylesheet, ctx)?,
         }
         Ok(book)
     }
 }
 
+/// Write `ONCE_STYLESHEET` to the book root, and warn if
+/// `output.html.additional-css` does not pick it up.
+pub fn write_once_stylesheet(stylesheet: &Stylesheet, ctx: &PreprocessorContext) -> Result<()> {
+    let Some(import_css) = stylesheet.import_css() else {
+        return Ok(());
+    };
+    write_if_changed(&ctx.root.join(ONCE_STYLESHEET), import_css.as_bytes())?;
+    let listed = ctx.config.html_config().is_some_and(|html| {
+        html.additional_css
+            .iter()
+            .any(|css| css == Path::new(ONCE_STYLESHEET))
+    });
+    if !listed && ctx.renderer == "html" {
+        warn!(
+            "[preprocessor.katex]: `inject-css = \"once\"` requires `additional-css = [\"{ONCE_STYLESHEET}\"]` under `[output.html]` in your `book.toml`."
+        );
+    }
+    Ok(())
+}
+
 /// Escape all Katex equations.
 pub fn process_all_chapters_escape(
     book: &mut Book,
     cfg: &KatexConfig,
-    stylesheet_header: &str,
-    _: &PreprocessorContext,
-) {
+    stylesheet: &Stylesheet,
+    ctx: &PreprocessorContext,
+) -> Result<()> {
     let extra_opts = cfg.build_extra_opts();
+    let environments = cfg.load_environments(&ctx.root);
+    let injection = cfg.css_injection();
     book.for_each_chapter_mut(|chapter| {
-        chapter.content = process_chapter_escape(&chapter.content, &extra_opts, stylesheet_header);
+        let _span = info_span!("chapter", path = ?chapter.path).entered();
+        let stylesheet_header = stylesheet.chapter_header(
+            injection,
+            chapter.path.as_deref(),
+            &chapter.content,
+            &extra_opts,
+        );
+        chapter.content = escape_chapter(
+            &chapter.content,
+            chapter.path.as_deref(),
+            &extra_opts,
+            &stylesheet_header,
+            &environments,
+            is_notation_chapter(cfg, chapter.path.as_deref()),
+        );
     });
+    Ok(())
 }
 
-/// Escape Katex equations.
+/// Escape Katex equations, after expanding custom `environments`
+/// and those defined in the chapter.
 pub fn process_chapter_escape(
     raw_content: &str,
     extra_opts: &ExtraOpts,
     stylesheet_header: &str,
+    environments: &Environments,
+) -> String {
+    escape_chapter(
+        raw_content,
+        None,
+        extra_opts,
+        stylesheet_header,
+        environments,
+        false,
+    )
+}
+
+/// `process_chapter_escape` for the chapter at `path`,
+/// escaping `|` in the formulas if they are `in_table` cells.
+fn escape_chapter(
+    raw_content: &str,
+    path: Option<&Path>,
+    extra_opts: &ExtraOpts,
+    stylesheet_header: &str,
+    environments: &Environments,
+    in_table: bool,
 ) -> String {
-    get_render_tasks(raw_content, stylesheet_header, extra_opts)
+    let chapter = replace_chapter_macros(raw_content, chapter_macros_script);
+    for block in &chapter.invalid {
+        if let Err(why) = &block.definitions {
+            error!("Ignoring `{CHAPTER_MACROS_INFO}` block: {why}");
+        }
+    }
+    let environments = chapter_environments(environments, &chapter.definitions);
+    let tasks = get_render_tasks(&chapter.content, stylesheet_header, extra_opts);
+    let items = expand_environments(&tasks, &chapter, path, &environments);
+    tasks
         .into_par_iter()
-        .map(|rend| match rend {
-            Render::Text(t) => t.into(),
-            Render::InlineTask(item) => {
-                escape_math_with_delimiter(item, &extra_opts.inline_delimiter).into()
-            }
-            Render::DisplayTask(item) => {
-                escape_math_with_delimiter(item, &extra_opts.block_delimiter).into()
+        .zip(items)
+        .map(|(rend, item)| {
+            let escaped = match rend {
+                Render::Text(_) => return item,
+                Render::InlineTask(_) => {
+                    escape_math_with_delimiter(&item, &extra_opts.inline_delimiter)
+                }
+                Render::DisplayTask(_) => {
+                    escape_math_with_delimiter(&item, &extra_opts.block_delimiter)
+                }
+            };
+            match in_table {
+                true => escape_table_pipes(&escaped).into_owned().into(),
+                false => escaped.into(),
             }
         })
         .collect::<Vec<Cow<_>>>()
         .join("")
 }
 
+/// `environments` with those defined in `chapter_macros` added.
+pub fn chapter_environments<'a>(
+    environments: &'a Environments,
+    chapter_macros: &[MacroDefinition],
+) -> Cow<'a, Environments> {
+    let mut environments = Cow::Borrowed(environments);
+    for definition in chapter_macros {
+        if definition.environment().is_some() {
+            environments.to_mut().define(definition);
+        }
+    }
+    environments
+}
+
+/// Content of each of the `tasks` from the content of `chapter` at `path`,
+/// with custom `environments` expanded in math blocks.
+/// Math blocks that fail to expand are kept as they are,
+/// and the error is logged with its line and column in the chapter as written.
+pub fn expand_environments<'a>(
+    tasks: &[Render<'a>],
+    chapter: &ReplacedChapter,
+    path: Option<&Path>,
+    environments: &Environments,
+) -> Vec<Cow<'a, str>> {
+    tasks
+        .iter()
+        .map(|task| match task {
+            Render::Text(t) => Cow::Borrowed(*t),
+            Render::InlineTask(item) | Render::DisplayTask(item) => {
+                environments.expand(item).unwrap_or_else(|why| {
+                    let offset = chapter.original_offset(offset_in(&chapter.content, item))
+                        + why.offset.unwrap_or(0);
+                    let (line, column) = line_and_column(chapter.original, offset);
+                    let path = path.unwrap_or(Path::new("<unnamed>"));
+                    error!(
+                        "{}:{line}:{column}: Leaving custom environments unexpanded: {why}",
+                        path.display()
+                    );
+                    Cow::Borrowed(*item)
+                })
+            }
+        })
+        .collect()
+}
+
+/// Byte offset of `part` in `content`, of which it is a slice,
+/// as math blocks of render tasks are.
+pub fn offset_in(content: &str, part: &str) -> usize {
+    let offset = (part.as_ptr() as usize).wrapping_sub(content.as_ptr() as usize);
+    debug_assert!(
+        offset <= content.len(),
+        "`part` is not a slice of `content`"
+    );
+    offset
+}
+
+/// Line and column numbers of byte `offset` in `content`.
+pub fn line_and_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |n| n + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
 /// A render job for chapter processing.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum Render<'a> {
@@ -96,6 +397,16 @@ pub enum Render<'a> {
     DisplayTask(&'a str),
 }
 
+/// Whether `raw_content` contains any math block.
+pub fn contains_math(raw_content: &str, extra_opts: &ExtraOpts) -> bool {
+    Scan::new(
+        raw_content,
+        &extra_opts.block_delimiter,
+        &extra_opts.inline_delimiter,
+    )
+    .any(|event| matches!(event, Event::InlineEnd(_) | Event::BlockEnd(_)))
+}
+
 /// Find all the `Render` tasks in `raw_content`.
 pub fn get_render_tasks<'a>(
     raw_content: &'a str,
@@ -109,7 +420,9 @@ pub fn get_render_tasks<'a>(
     );
 
     let mut rendering = Vec::new();
-    rendering.push(Render::Text(stylesheet_header));
+    if !stylesheet_header.is_empty() {
+        rendering.push(Render::Text(stylesheet_header));
+    }
 
     let mut checkpoint = 0;
     for event in scan {
@@ -0,0 +1,204 @@
+//! Rename a macro across the book: in math blocks, `katex-macros` blocks,
+//! and macros files.
+use std::ops::Range;
+
+use similar::TextDiff;
+
+use super::*;
+
+/// How a source writes the backslash of control sequences.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Syntax {
+    /// As is, in LaTeX, math blocks and `.txt` macros files.
+    Tex,
+    /// Escaped as `\\` in JSON strings.
+    Json,
+    /// As is in literal strings, escaped as `\\` in basic strings.
+    Toml,
+}
+
+impl Syntax {
+    /// Syntax of the macros file at `path`, following its extension.
+    pub fn of_macros_file(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Syntax::Json,
+            Some("toml") => Syntax::Toml,
+            _ => Syntax::Tex,
+        }
+    }
+
+    /// Whether a control sequence name after `n_backslashes` backslashes
+    /// is a control sequence, rather than, e.g., `\\` followed by text.
+    fn is_control_sequence(self, n_backslashes: usize) -> bool {
+        match self {
+            Syntax::Tex => n_backslashes % 2 == 1,
+            Syntax::Json => n_backslashes % 4 == 2,
+            Syntax::Toml => !n_backslashes.is_multiple_of(4),
+        }
+    }
+}
+
+/// A file rewritten by renaming a macro.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rewrite {
+    /// Path to the file.
+    pub path: PathBuf,
+    /// Content before renaming.
+    pub before: String,
+    /// Content after renaming.
+    pub after: String,
+}
+
+impl Rewrite {
+    /// Unified diff from `before` to `after`, with paths relative to `root`.
+    pub fn unified_diff(&self, root: &Path) -> String {
+        let path = self.path.strip_prefix(root).unwrap_or(&self.path);
+        TextDiff::from_lines(&self.before, &self.after)
+            .unified_diff()
+            .header(
+                &format!("a/{}", path.display()),
+                &format!("b/{}", path.display()),
+            )
+            .to_string()
+    }
+}
+
+/// Whether `name` is a macro name made of letters, such as `\R`.
+pub fn is_macro_name(name: &str) -> bool {
+    name.strip_prefix('\\').is_some_and(|letters| {
+        !letters.is_empty() && letters.chars().all(|c| c.is_ascii_alphabetic() || c == '@')
+    })
+}
+
+/// Replace the control sequence `old` with `new` in `source` written in `syntax`.
+/// `\older` and `\\old`, a line break followed by text in LaTeX, are left alone.
+pub fn rename_control_sequence<'a>(
+    source: &'a str,
+    old: &str,
+    new: &str,
+    syntax: Syntax,
+) -> Cow<'a, str> {
+    let letters = &old[1..];
+    let mut renamed = String::new();
+    let mut checkpoint = 0;
+    for (index, _) in source.match_indices(letters) {
+        let n_backslashes = source[..index].len() - source[..index].trim_end_matches('\\').len();
+        let end = index + letters.len();
+        let boundary = !source[end..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '@');
+        if boundary && syntax.is_control_sequence(n_backslashes) {
+            renamed.push_str(&source[checkpoint..index]);
+            renamed.push_str(&new[1..]);
+            checkpoint = end;
+        }
+    }
+    if checkpoint == 0 {
+        return source.into();
+    }
+    renamed.push_str(&source[checkpoint..]);
+    renamed.into()
+}
+
+/// Rename `old` to `new` in the math blocks and `katex-macros` blocks
+/// of the chapter `raw_content`, leaving text and code alone.
+pub fn rename_in_chapter<'a>(
+    raw_content: &'a str,
+    old: &str,
+    new: &str,
+    extra_opts: &ExtraOpts,
+) -> Cow<'a, str> {
+    let blocks: Vec<_> = find_chapter_macros(raw_content)
+        .into_iter()
+        .map(|block| block.range)
+        .collect();
+    // Math is not looked for in `~~~` fences, so it may be found
+    // inside `katex-macros` blocks, which are renamed as a whole.
+    let in_block = |range: &Range<usize>| {
+        blocks
+            .iter()
+            .any(|block| range.start < block.end && block.start < range.end)
+    };
+    let mut ranges: Vec<Range<usize>> = get_render_tasks(raw_content, "", extra_opts)
+        .into_iter()
+        .filter_map(|task| match task {
+            Render::Text(_) => None,
+            Render::InlineTask(item) | Render::DisplayTask(item) => {
+                let start = offset_in(raw_content, item);
+                Some(start..start + item.len())
+            }
+        })
+        .filter(|range| !in_block(range))
+        .chain(blocks.iter().cloned())
+        .collect();
+    ranges.sort_by_key(|range| range.start);
+
+    let mut renamed = String::with_capacity(raw_content.len());
+    let mut checkpoint = 0;
+    for range in ranges {
+        renamed.push_str(&raw_content[checkpoint..range.start]);
+        renamed.push_str(&rename_control_sequence(
+            &raw_content[range.clone()],
+            old,
+            new,
+            Syntax::Tex,
+        ));
+        checkpoint = range.end;
+    }
+    renamed.push_str(&raw_content[checkpoint..]);
+    match renamed == raw_content {
+        true => raw_content.into(),
+        false => renamed.into(),
+    }
+}
+
+/// Rename the macro `old` to `new` in the book at `root`:
+/// in every Markdown file of its source directory, and in its macros files.
+/// Return the files that change, without writing them.
+pub fn rename_macro(root: &Path, old: &str, new: &str) -> Result<Vec<Rewrite>> {
+    for name in [old, new] {
+        if !is_macro_name(name) {
+            return Err(mdbook_preprocessor::errors::Error::msg(format!(
+                "`{name}` is not a macro name made of a backslash and letters"
+            )));
+        }
+    }
+    let (cfg, src) = get_book_config(root)?;
+    let (definitions, _) = cfg.load_definitions(root)?;
+    if definitions.iter().any(|d| d.name == new) {
+        return Err(mdbook_preprocessor::errors::Error::msg(format!(
+            "`{new}` is already defined"
+        )));
+    }
+    if !definitions.iter().any(|d| d.name == old) {
+        warn!("`{old}` is not defined in `macros`, renaming its uses anyway.");
+    }
+    let mut sources = cfg.macros.iter().flat_map(Macros::sources);
+    if sources.any(|source| matches!(source, MacroSource::Inline(_))) {
+        warn!("Macros in tables in `book.toml` are not renamed, please rename `{old}` there.");
+    }
+
+    let extra_opts = cfg.build_extra_opts();
+    let mut rewrites = Vec::new();
+    for path in markdown_files(&src)? {
+        let path = src.join(path);
+        let before = std::fs::read_to_string(&path)?;
+        if let Cow::Owned(after) = rename_in_chapter(&before, old, new, &extra_opts) {
+            rewrites.push(Rewrite {
+                path,
+                before,
+                after,
+            });
+        }
+    }
+    for path in get_macro_paths(root, &cfg.macros) {
+        let before = std::fs::read_to_string(&path)?;
+        let syntax = Syntax::of_macros_file(&path);
+        if let Cow::Owned(after) = rename_control_sequence(&before, old, new, syntax) {
+            rewrites.push(Rewrite {
+                path,
+                before,
+                after,
+            });
+        }
+    }
+    Ok(rewrites)
+}
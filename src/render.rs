@@ -1,54 +1,285 @@
 //! Render KaTeX math block to HTML
+use std::time::Duration;
+
 use katex::{Error, Opts};
 
 use super::*;
 
-pub use {cfg::*, preprocess::*};
+pub use {cfg::*, preprocess::*, report::*, timeout::*};
 
 mod cfg;
 mod preprocess;
+mod report;
+mod timeout;
+
+/// A rendered math block.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rendered {
+    /// HTML of the math block,
+    /// or the math block itself between its delimiters if rendering failed.
+    pub html: String,
+    /// What KaTeX reported if rendering failed.
+    pub error: Option<String>,
+}
 
-/// Render a math block `item` into HTML following `opts`.
-/// Wrap result in `<data>` tag if `extra_opts.include_src`.
-#[instrument(skip(opts, extra_opts, display))]
-pub fn render(item: &str, opts: Opts, extra_opts: ExtraOpts, display: bool) -> String {
+/// Render a math block `item` into HTML following `opts`,
+/// or reuse its rendering from `extra_opts.cache`.
+/// `source` is the math block as written, before expanding custom environments
+/// into `item`; it is what is included following `extra_opts.include_src`.
+#[instrument(skip(source, opts, extra_opts, display))]
+pub fn render(
+    item: &str,
+    source: &str,
+    opts: &Opts,
+    extra_opts: &ExtraOpts,
+    display: bool,
+) -> Rendered {
     let mut rendered_content = String::new();
 
     // try to render equation
-    match katex::render_with_opts(item, opts) {
+    let error = match render_cached(item, opts, extra_opts, display) {
         Ok(rendered) => {
-            let rendered = rendered.replace('\n', " ");
-            if extra_opts.include_src {
-                // Wrap around with `data.katex-src` tag.
-                rendered_content.push_str(r#"<data class="katex-src" value=""#);
-                rendered_content.push_str(&item.replace('"', r#"\""#).replace('\n', r"&#10;"));
-                rendered_content.push_str(r#"">"#);
-                rendered_content.push_str(&rendered);
-                rendered_content.push_str(r"</data>");
-            } else {
-                rendered_content.push_str(&rendered);
-            }
+            rendered_content.push_str(&include_source(source, rendered, extra_opts.include_src));
+            None
+        }
+        // if rendering fails, show it following `extra_opts.error_display`
+        Err(message) => {
+            rendered_content.push_str(&error_html(source, item, display, extra_opts, &message));
+            Some(message)
+        }
+    };
+
+    Rendered {
+        html: rendered_content,
+        error,
+    }
+}
+
+/// Include the source of the math block `item` in its `rendered` HTML
+/// following `inclusion`.
+pub fn include_source(item: &str, rendered: String, inclusion: SourceInclusion) -> String {
+    let root = r#"<span class="katex">"#;
+    match inclusion {
+        SourceInclusion::None => rendered,
+        SourceInclusion::Data => {
+            format!(r#"<data class="katex-src" value="{}">{rendered}</data>"#, escape_attribute(item))
         }
-        // if rendering fails, keep the unrendered equation
-        Err(why) => {
-            match why {
-                Error::JsExecError(why) => {
-                    warn!("Rendering failed, keeping the original content: {why}")
-                }
-                _ => error!(
-                    ?why,
-                    "Unexpected rendering failure, keeping the original content."
-                ),
-            }
-            let delimiter = match display {
-                true => &extra_opts.block_delimiter,
-                false => &extra_opts.inline_delimiter,
-            };
-            rendered_content.push_str(&delimiter.left);
-            rendered_content.push_str(item);
-            rendered_content.push_str(&delimiter.right);
+        // KaTeX already annotates its MathML output.
+        SourceInclusion::Annotation if rendered.contains(r#"<annotation encoding="application/x-tex">"#) => {
+            rendered
         }
+        SourceInclusion::Annotation => rendered.replacen(
+            root,
+            &format!(
+                r#"{root}<span class="katex-mathml"><math xmlns="http://www.w3.org/1998/Math/MathML"><semantics><mrow></mrow><annotation encoding="application/x-tex">{}</annotation></semantics></math></span>"#,
+                escape_attribute(item)
+            ),
+            1,
+        ),
+        SourceInclusion::Attribute => rendered.replacen(
+            root,
+            &format!(r#"<span class="katex" data-latex="{}">"#, escape_attribute(item)),
+            1,
+        ),
     }
+}
 
-    rendered_content
+/// HTML shown for the math block `source` that failed to render with `message`,
+/// following `extra_opts.error_display`.
+/// Fallback formulas are rendered in the browser, which does not know custom
+/// environments, so they hold `item`, the source with environments expanded.
+pub fn error_html(
+    source: &str,
+    item: &str,
+    display: bool,
+    extra_opts: &ExtraOpts,
+    message: &str,
+) -> String {
+    let delimiter = match display {
+        true => &extra_opts.block_delimiter,
+        false => &extra_opts.inline_delimiter,
+    };
+    match &extra_opts.error_display {
+        ErrorDisplay::Raw => format!("{}{source}{}", delimiter.left, delimiter.right),
+        ErrorDisplay::Fallback => fallback_span(item, delimiter),
+        ErrorDisplay::Span { color } => format!(
+            r#"<span class="katex-error" title="{}" style="color: {}; font-family: monospace; white-space: pre-wrap;">{}</span>"#,
+            escape_html(message),
+            escape_html(color),
+            escape_markdown(source),
+        ),
+        ErrorDisplay::Template(template) => {
+            fill_template(template, &escape_markdown(source), &escape_html(message))
+        }
+    }
+}
+
+/// Replace `{source}` and `{message}` in `template` in one pass,
+/// so placeholders in their values are left alone.
+fn fill_template(template: &str, source: &str, message: &str) -> String {
+    let mut filled = String::with_capacity(template.len() + source.len() + message.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (value, placeholder) = if rest.starts_with("{source}") {
+            (source, "{source}")
+        } else if rest.starts_with("{message}") {
+            (message, "{message}")
+        } else {
+            ("{", "{")
+        };
+        filled.push_str(value);
+        rest = &rest[placeholder.len()..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+/// Escape `text` for HTML text and attribute values.
+pub fn escape_html(text: &str) -> Cow<'_, str> {
+    if !text.contains(['&', '<', '>', '"', '\'']) {
+        return text.into();
+    }
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.into()
+}
+
+/// Escape `text` for HTML, with line breaks as character references
+/// so that Markdown does not split the HTML at blank lines.
+pub fn escape_attribute(text: &str) -> String {
+    escape_html(text).replace('\n', "&#10;")
+}
+
+/// Escape `text` for HTML inside Markdown, with the characters Markdown
+/// would interpret, e.g., as escapes or emphasis, as character references.
+pub fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 16);
+    for c in escape_attribute(text).chars() {
+        match c {
+            '\\' => escaped.push_str("&#92;"),
+            '*' => escaped.push_str("&#42;"),
+            '_' => escaped.push_str("&#95;"),
+            '`' => escaped.push_str("&#96;"),
+            '[' => escaped.push_str("&#91;"),
+            '$' => escaped.push_str("&#36;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Message of the KaTeX error `why`, without the formula KaTeX quotes after
+/// the position of the error or "at end of input", e.g.,
+/// "KaTeX parse error: Undefined control sequence: \foo at position 1".
+pub fn katex_message(why: &Error) -> String {
+    let Error::JsExecError(detail) = why else {
+        return why.to_string();
+    };
+    // The JS error is formatted as `String("...")`, with Rust escapes.
+    let detail = detail
+        .strip_prefix("String(\"")
+        .and_then(|detail| detail.strip_suffix("\")"))
+        .unwrap_or(detail);
+    let detail = detail.strip_prefix("ParseError: ").unwrap_or(detail);
+    let position = [" at position ", " at end of input"]
+        .iter()
+        .find_map(|marker| detail.find(marker));
+    let message = match position {
+        Some(start) => match detail[start..].find(':') {
+            Some(end) => &detail[..start + end],
+            None => detail,
+        },
+        None => detail,
+    };
+    message.replace(r"\\", r"\").replace(r#"\""#, "\"")
+}
+
+/// Render `item` with KaTeX within the limits of `extra_opts`,
+/// through `extra_opts.cache` if any.
+/// Return the HTML, or what went wrong.
+fn render_cached(
+    item: &str,
+    opts: &Opts,
+    extra_opts: &ExtraOpts,
+    display: bool,
+) -> Result<String, String> {
+    check_length(item, extra_opts)?;
+    let Some(cache) = &extra_opts.cache else {
+        return render_katex(item, opts, extra_opts.render_timeout);
+    };
+    let key = cache.key(item, display);
+    if let Some(rendered) = cache.get(&key) {
+        return Ok(rendered);
+    }
+    let rendered = render_katex(item, opts, extra_opts.render_timeout)?;
+    cache.put(&key, &rendered);
+    Ok(rendered)
+}
+
+/// Render `item` with KaTeX within the limits of `extra_opts`, without cache.
+/// Return the HTML, or what went wrong.
+pub fn render_limited(item: &str, opts: &Opts, extra_opts: &ExtraOpts) -> Result<String, String> {
+    check_length(item, extra_opts)?;
+    render_katex(item, opts, extra_opts.render_timeout)
+}
+
+/// Fail if `item` is longer than `extra_opts.max_formula_length`.
+fn check_length(item: &str, extra_opts: &ExtraOpts) -> Result<(), String> {
+    match extra_opts.max_formula_length {
+        Some(max) if item.len() > max => Err(format!(
+            "the formula is {} bytes long, more than `max-formula-length` = {max}",
+            item.len()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Render `item` with KaTeX, giving up after `timeout` if any.
+fn render_katex(item: &str, opts: &Opts, timeout: Option<Duration>) -> Result<String, String> {
+    let rendered = match timeout {
+        Some(timeout) => render_with_timeout(item, opts, timeout)?,
+        None => render_guarded(item, opts)?,
+    };
+    Ok(rendered.replace('\n', " "))
+}
+
+/// Render `item` with KaTeX following `opts`,
+/// turning panics of the JS engine into failures.
+pub fn render_guarded(item: &str, opts: &Opts) -> Result<String, String> {
+    catch_panic(|| katex::render_with_opts(item, opts).map_err(katex_failure))
+}
+
+/// Run `f`, turning a panic into a failure with its message.
+pub(crate) fn catch_panic<F>(f: F) -> Result<String, String>
+where
+    F: FnOnce() -> Result<String, String>,
+{
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let why = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        error!(why, "The JS engine panicked.");
+        Err(format!("the JS engine panicked: {why}"))
+    })
+}
+
+/// Message of the KaTeX error `why`, logged if it is not a mere invalid formula.
+fn katex_failure(why: Error) -> String {
+    if !matches!(why, Error::JsExecError(_)) {
+        error!(?why, "Unexpected rendering failure.");
+    }
+    katex_message(&why)
 }
@@ -21,14 +21,13 @@ Defaulting to `html`. Other valid choices for output are `mathml` and `htmlAndMa
 
     /// From `root`, load macros and generate configuration options
     /// `(inline_opts, display_opts)`.
-    pub fn build_opts<P>(&self, root: P) -> (katex::Opts, katex::Opts)
+    pub fn build_opts<P>(&self, root: P) -> Result<(katex::Opts, katex::Opts)>
     where
         P: AsRef<Path>,
     {
-        // load macros as a HashMap
-        let macros = load_macros(root, &self.macros);
+        let (definitions, _) = self.load_definitions(root)?;
 
-        self.build_opts_from_macros(macros)
+        Ok(self.build_opts_from_macros(macro_map(definitions)))
     }
 
     /// Given `macros`, generate `(inline_opts, display_opts)`.
@@ -60,48 +59,62 @@ Defaulting to `html`. Other valid choices for output are `mathml` and `htmlAndMa
     }
 }
 
-/// Load macros from `root`/`macros_path` into a `HashMap`.
-fn load_macros<P>(root: P, macros_path: &Option<String>) -> HashMap<String, String>
-where
-    P: AsRef<Path>,
-{
-    // load macros as a HashMap
-    let mut map = HashMap::new();
-    if let Some(path) = get_macro_path(root, macros_path) {
-        let macro_str = load_as_string(&path);
-        for couple in macro_str.split('\n') {
-            // only consider lines starting with a backslash
-            if let Some('\\') = couple.chars().next() {
-                let couple: Vec<&str> = couple.splitn(2, ':').collect();
-                map.insert(String::from(couple[0]), String::from(couple[1]));
-            }
+impl KatexConfig {
+    /// Size the global thread pool rendering formulas to `jobs` threads.
+    /// KaTeX keeps one JS engine per thread, created on its first formula
+    /// and reused for the following ones, so `jobs` is also the number of engines.
+    pub fn init_thread_pool(&self) {
+        let Some(jobs) = self.jobs else {
+            return;
+        };
+        if let Err(why) = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()
+        {
+            warn!("[preprocessor.katex]: Couldn't use {jobs} threads to render formulas: {why}");
         }
     }
-    map
-}
 
-/// Absolute path of the macro file.
-pub fn get_macro_path<P>(root: P, macros_path: &Option<String>) -> Option<PathBuf>
-where
-    P: AsRef<Path>,
-{
-    macros_path
-        .as_ref()
-        .map(|path| root.as_ref().join(PathBuf::from(path)))
+    /// Render cache of the book at `root` for formulas rendered with
+    /// the KaTeX options and `definitions`, if `cache` is enabled.
+    pub fn render_cache(
+        &self,
+        root: &Path,
+        definitions: &[MacroDefinition],
+    ) -> Option<RenderCache> {
+        if !self.cache {
+            return None;
+        }
+        let macros: BTreeMap<_, _> = macro_map(definitions.to_vec()).into_iter().collect();
+        let options = serde_json::json!({
+            "katex": katex::KATEX_VERSION,
+            "output": self.output,
+            "leqno": self.leqno,
+            "fleqn": self.fleqn,
+            "throw_on_error": self.throw_on_error,
+            "error_color": self.error_color,
+            "min_rule_thickness": self.min_rule_thickness,
+            "max_size": self.max_size,
+            "max_expand": self.max_expand,
+            "trust": self.trust,
+            "macros": macros,
+        });
+        Some(RenderCache::new(
+            self.cache_dir(root),
+            self.cache_max_size.saturating_mul(1 << 20),
+            &options.to_string(),
+        ))
+    }
 }
 
-/// Read file at `path`.
-pub fn load_as_string(path: &Path) -> String {
-    let display = path.display();
-
-    let mut file = match File::open(path) {
-        Err(why) => panic!("couldn't open {display}: {why}"),
-        Ok(file) => file,
-    };
-
-    let mut string = String::new();
-    if let Err(why) = file.read_to_string(&mut string) {
-        panic!("couldn't read {display}: {why}")
-    };
-    string
+/// Macro names to expansions for KaTeX, from `definitions`.
+/// Later definitions override earlier ones with the same name.
+pub fn macro_map(definitions: Vec<MacroDefinition>) -> HashMap<String, String> {
+    definitions
+        .into_iter()
+        .map(|definition| {
+            let expansion = definition.katex_expansion();
+            (definition.name, expansion)
+        })
+        .collect()
 }
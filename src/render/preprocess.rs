@@ -1,48 +1,399 @@
 //! Preprocessing and pre-rendering with KaTeX.
+use std::ops::Range;
+
 use katex::Opts;
 
 use super::*;
 
-/// Render all Katex equations.
+/// Render all Katex equations, and return the formulas that fail to render.
+/// Identical formulas across the book are rendered only once.
 pub fn process_all_chapters_prerender(
     book: &mut Book,
     cfg: &KatexConfig,
-    stylesheet_header: &str,
+    stylesheet: &Stylesheet,
     ctx: &PreprocessorContext,
-) {
-    let extra_opts = cfg.build_extra_opts();
-    let (inline_opts, display_opts) = cfg.build_opts(&ctx.root);
+) -> Result<Vec<RenderFailure>> {
+    cfg.init_thread_pool();
+    let mut extra_opts = cfg.build_extra_opts();
+    let (definitions, environments) = cfg.load_definitions(&ctx.root)?;
+    extra_opts.cache = cfg.render_cache(&ctx.root, &definitions);
+    let macros = macro_map(definitions);
+    let book_macros: BTreeMap<_, _> = macros.clone().into_iter().collect();
+    let (inline_opts, display_opts) = cfg.build_opts_from_macros(macros);
+    let injection = cfg.css_injection();
 
+    // Scan chapters in parallel, then render the formulas of the whole book
+    // in parallel, and splice them back into each chapter.
+    let mut contents = Vec::new();
     book.for_each_chapter_mut(|chapter| {
-        chapter.content = process_chapter_prerender(
-            &chapter.content,
-            inline_opts.clone(),
-            display_opts.clone(),
-            stylesheet_header,
-            &extra_opts,
-        );
+        let content = std::mem::take(&mut chapter.content);
+        contents.push((chapter.path.clone(), content));
+    });
+    let scanned: Vec<_> = contents
+        .par_iter()
+        .map(|(path, content)| {
+            let _span = info_span!("chapter", ?path).entered();
+            let stylesheet_header =
+                stylesheet.chapter_header(injection, path.as_deref(), content, &extra_opts);
+            ScannedChapter::new(
+                content,
+                path.as_deref(),
+                &stylesheet_header,
+                &extra_opts,
+                &environments,
+            )
+        })
+        .collect();
+    let mut queue = FormulaQueue::default();
+    let mut chapters: VecDeque<_> = scanned
+        .into_iter()
+        .map(|scanned| queue.push_scanned(scanned))
+        .collect();
+    info!(
+        "Rendering {} distinct formulas out of {}.",
+        queue.formulas.len(),
+        queue.n_pushed
+    );
+    let rendered = queue.render(&inline_opts, &display_opts, &extra_opts);
+    let mut failures = Vec::new();
+    let mut contents = contents.into_iter();
+    book.for_each_chapter_mut(|chapter| {
+        let queued = chapters.pop_front().expect("Same chapters as above");
+        let (path, raw_content) = contents.next().expect("Same chapters as above");
+        let chapter_failures = queued.failures(path, &raw_content, &rendered);
+        chapter.content = match is_notation_chapter(cfg, chapter.path.as_deref()) {
+            true => queued.splice_with(&rendered, escape_table_pipes),
+            false => queued.splice(&rendered),
+        };
+        // In hybrid mode, formulas that failed are rendered in the browser.
+        if extra_opts.error_display == ErrorDisplay::Fallback && !chapter_failures.is_empty() {
+            let mut macros = book_macros.clone();
+            macros.extend(queued.macros.iter().cloned());
+            chapter
+                .content
+                .push_str(&fallback_bootstrap(&macros, &extra_opts));
+        }
+        failures.extend(chapter_failures);
     });
+
+    if let Some(cache) = &extra_opts.cache {
+        if let Err(why) = cache.trim() {
+            warn!(dir = ?cache.dir, %why, "Couldn't trim the render cache.");
+        }
+    }
+    Ok(failures)
 }
 
 /// Render Katex equations in a `Chapter` as HTML, and add the Katex CSS.
+/// Formulas that fail to render are kept as is.
+/// Macros in `katex-macros` blocks of the chapter override those in the options,
+/// and custom `environments` are expanded before rendering.
 pub fn process_chapter_prerender(
     raw_content: &str,
     inline_opts: Opts,
     display_opts: Opts,
     stylesheet_header: &str,
     extra_opts: &ExtraOpts,
+    environments: &Environments,
 ) -> String {
-    get_render_tasks(raw_content, stylesheet_header, extra_opts)
-        .into_par_iter()
-        .map(|rend| match rend {
-            Render::Text(t) => t.into(),
-            Render::InlineTask(item) => {
-                render(item, inline_opts.clone(), extra_opts.clone(), false).into()
-            }
-            Render::DisplayTask(item) => {
-                render(item, display_opts.clone(), extra_opts.clone(), true).into()
-            }
-        })
-        .collect::<Vec<Cow<_>>>()
-        .join("")
+    let scanned = ScannedChapter::new(
+        raw_content,
+        None,
+        stylesheet_header,
+        extra_opts,
+        environments,
+    );
+    let mut queue = FormulaQueue::default();
+    let queued = queue.push_scanned(scanned);
+    let rendered = queue.render(&inline_opts, &display_opts, extra_opts);
+    queued.splice(&rendered)
+}
+
+/// Macros added to the options, as names and KaTeX expansions.
+type Variant = Vec<(String, String)>;
+
+/// A formula as the index of its variant of the options,
+/// whether it is displayed, its source after expanding environments,
+/// and its source as written.
+type Formula = (usize, bool, String, String);
+
+/// Part of a chapter, either text or a formula of type `F`.
+#[derive(Clone, Debug)]
+enum Piece<F> {
+    /// Text kept as is.
+    Text(String),
+    /// A formula to render.
+    Formula(F),
+}
+
+/// Content of a chapter split into text and formulas,
+/// which can be scanned in parallel with other chapters.
+#[derive(Clone, Debug, Default)]
+pub struct ScannedChapter {
+    /// Variants of the options used in the chapter, in order.
+    variants: Vec<Variant>,
+    /// Pieces, with formulas and their byte range in the chapter,
+    /// the variants being indexes in `variants`.
+    pieces: Vec<Piece<(Formula, Range<usize>)>>,
+    /// Byte ranges of invalid `katex-macros` blocks, left as they are,
+    /// and what is wrong with them.
+    invalid_macros: Vec<(Range<usize>, String)>,
+}
+
+impl ScannedChapter {
+    /// Split the chapter `raw_content` at `path` into text and formulas, with the
+    /// macros of its `katex-macros` blocks and, if `extra_opts.persist_macros`,
+    /// of global definitions in formulas preceding each formula.
+    /// Custom `environments` are expanded in the formulas.
+    pub fn new(
+        raw_content: &str,
+        path: Option<&Path>,
+        stylesheet_header: &str,
+        extra_opts: &ExtraOpts,
+        environments: &Environments,
+    ) -> Self {
+        let chapter = replace_chapter_macros(raw_content, |_| String::new());
+        let invalid_macros = chapter
+            .invalid
+            .iter()
+            .filter_map(|block| {
+                let MacroErrors(errors) = block.definitions.as_ref().err()?;
+                let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+                let message = format!(
+                    "invalid `{CHAPTER_MACROS_INFO}` block, {}",
+                    errors.join(", ")
+                );
+                Some((block.range.clone(), message))
+            })
+            .collect();
+        let environments = chapter_environments(environments, &chapter.definitions);
+        let mut variant: Variant = chapter
+            .definitions
+            .iter()
+            .filter(|definition| definition.environment().is_none())
+            .map(|definition| (definition.name.clone(), definition.katex_expansion()))
+            .collect();
+        let mut variants = vec![variant.clone()];
+
+        let tasks = get_render_tasks(&chapter.content, stylesheet_header, extra_opts);
+        let items = expand_environments(&tasks, &chapter, path, &environments);
+        let pieces = tasks
+            .iter()
+            .zip(items)
+            .map(|(task, item)| {
+                let (source, display) = match task {
+                    Render::Text(_) => return Piece::Text(item.into_owned()),
+                    Render::InlineTask(source) => (source, false),
+                    Render::DisplayTask(source) => (source, true),
+                };
+                let start = chapter.original_offset(offset_in(&chapter.content, source));
+                let range = start..start + source.len();
+                let globals = match extra_opts.persist_macros {
+                    true => parse_global_macros(&item),
+                    false => Vec::new(),
+                };
+                let formula = (
+                    variants.len() - 1,
+                    display,
+                    item.into_owned(),
+                    (*source).to_owned(),
+                );
+                let piece = Piece::Formula((formula, range));
+                if !globals.is_empty() {
+                    variant.extend(globals.into_iter().map(|definition| {
+                        let expansion = definition.katex_expansion();
+                        (definition.name, expansion)
+                    }));
+                    variants.push(variant.clone());
+                }
+                piece
+            })
+            .collect();
+        Self {
+            variants,
+            pieces,
+            invalid_macros,
+        }
+    }
+}
+
+/// Formulas to render, each distinct formula once.
+#[derive(Debug, Default)]
+pub struct FormulaQueue {
+    /// Variants of the options.
+    variants: Vec<Variant>,
+    /// Index of each variant in `variants`.
+    variant_indexes: HashMap<Variant, usize>,
+    /// Distinct formulas, the variants being indexes in `variants`.
+    formulas: Vec<Formula>,
+    /// Index of each formula in `formulas`.
+    formula_indexes: HashMap<Formula, usize>,
+    /// Number of formulas pushed, including duplicates.
+    n_pushed: usize,
+}
+
+/// Content of a chapter whose formulas are in a `FormulaQueue`.
+#[derive(Clone, Debug, Default)]
+pub struct QueuedChapter {
+    /// Pieces, with formulas as their index in the queue
+    /// and their byte range in the chapter.
+    pieces: Vec<Piece<(usize, Range<usize>)>>,
+    /// Macros of the chapter added to the options,
+    /// including global definitions in its formulas if any.
+    pub macros: Variant,
+    /// Invalid `katex-macros` blocks, as in `ScannedChapter`.
+    invalid_macros: Vec<(Range<usize>, String)>,
+}
+
+impl FormulaQueue {
+    /// Index of `variant` in the queue.
+    fn variant(&mut self, variant: Variant) -> usize {
+        *self
+            .variant_indexes
+            .entry(variant)
+            .or_insert_with_key(|variant| {
+                self.variants.push(variant.clone());
+                self.variants.len() - 1
+            })
+    }
+
+    /// Queue `formula`, and return its index.
+    fn push(&mut self, formula: Formula) -> usize {
+        self.n_pushed += 1;
+        *self
+            .formula_indexes
+            .entry(formula)
+            .or_insert_with_key(|formula| {
+                self.formulas.push(formula.clone());
+                self.formulas.len() - 1
+            })
+    }
+
+    /// Queue the formulas of the `scanned` chapter.
+    pub fn push_scanned(&mut self, scanned: ScannedChapter) -> QueuedChapter {
+        let macros = scanned.variants.last().cloned().unwrap_or_default();
+        let variants: Vec<_> = scanned
+            .variants
+            .into_iter()
+            .map(|variant| self.variant(variant))
+            .collect();
+        let pieces = scanned
+            .pieces
+            .into_iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => Piece::Text(text),
+                Piece::Formula(((variant, display, item, source), range)) => {
+                    let formula = (variants[variant], display, item, source);
+                    Piece::Formula((self.push(formula), range))
+                }
+            })
+            .collect();
+        QueuedChapter {
+            pieces,
+            macros,
+            invalid_macros: scanned.invalid_macros,
+        }
+    }
+
+    /// Render the queued formulas in parallel, with macros of their variant
+    /// added to `inline_opts` and `display_opts`.
+    /// The options are built once per variant and shared by its formulas.
+    pub fn render(
+        self,
+        inline_opts: &Opts,
+        display_opts: &Opts,
+        extra_opts: &ExtraOpts,
+    ) -> Vec<Rendered> {
+        debug!(
+            "Rendering {} distinct formulas out of {}.",
+            self.formulas.len(),
+            self.n_pushed
+        );
+        let variants: Vec<_> = self
+            .variants
+            .iter()
+            .map(|macros| {
+                let (mut inline_opts, mut display_opts) =
+                    (inline_opts.clone(), display_opts.clone());
+                let mut extra_opts = extra_opts.clone();
+                for (name, expansion) in macros {
+                    inline_opts.add_macro(name.clone(), expansion.clone());
+                    display_opts.add_macro(name.clone(), expansion.clone());
+                }
+                extra_opts.cache = extra_opts.cache.map(|cache| cache.with_macros(macros));
+                (inline_opts, display_opts, extra_opts)
+            })
+            .collect();
+        self.formulas
+            .into_par_iter()
+            .map(|(variant, display, item, source)| {
+                let (inline_opts, display_opts, extra_opts) = &variants[variant];
+                let opts = match display {
+                    true => display_opts,
+                    false => inline_opts,
+                };
+                render(&item, &source, opts, extra_opts, display)
+            })
+            .collect()
+    }
+}
+
+impl QueuedChapter {
+    /// Content of the chapter with formulas replaced by their
+    /// `rendered` HTML, indexed as in the `FormulaQueue`.
+    pub fn splice(&self, rendered: &[Rendered]) -> String {
+        self.splice_with(rendered, Cow::Borrowed)
+    }
+
+    /// Content of the chapter with formulas replaced by `process`
+    /// applied to their `rendered` HTML, indexed as in the `FormulaQueue`.
+    pub fn splice_with<'a, F>(&self, rendered: &'a [Rendered], process: F) -> String
+    where
+        F: Fn(&'a str) -> Cow<'a, str>,
+    {
+        self.pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.as_str().into(),
+                Piece::Formula((index, _)) => process(&rendered[*index].html),
+            })
+            .collect::<Vec<Cow<_>>>()
+            .concat()
+    }
+
+    /// Formulas of the chapter at `path` with `raw_content` that failed to
+    /// render, according to `rendered`, indexed as in the `FormulaQueue`,
+    /// and invalid `katex-macros` blocks, in order.
+    pub fn failures(
+        &self,
+        path: Option<PathBuf>,
+        raw_content: &str,
+        rendered: &[Rendered],
+    ) -> Vec<RenderFailure> {
+        let formulas = self.pieces.iter().filter_map(|piece| {
+            let Piece::Formula((index, range)) = piece else {
+                return None;
+            };
+            Some((range, rendered[*index].error.clone()?))
+        });
+        let invalid_macros = self
+            .invalid_macros
+            .iter()
+            .map(|(range, message)| (range, message.clone()));
+        let mut failures: Vec<_> = formulas
+            .chain(invalid_macros)
+            .map(|(range, message)| {
+                let (line, column) = line_and_column(raw_content, range.start);
+                RenderFailure {
+                    chapter: path.clone(),
+                    line,
+                    column,
+                    formula: raw_content[range.clone()].to_owned(),
+                    message,
+                }
+            })
+            .collect();
+        failures.sort_by_key(|failure| (failure.line, failure.column));
+        failures
+    }
 }
@@ -0,0 +1,135 @@
+//! Find undefined control sequences by rendering with KaTeX.
+use std::collections::HashSet;
+
+use katex::Opts;
+
+use super::*;
+
+/// Maximum number of undefined control sequences reported per math block.
+const MAX_UNDEFINED_PER_FORMULA: usize = 16;
+
+/// Uses of control sequences undefined given `definitions` and `environments`
+/// in `chapters` of the book at `root`, found by rendering each math block,
+/// through the render cache if enabled.
+/// If the book was just rendered with `failures`, and KaTeX threw on errors,
+/// only the math blocks that failed are rendered again.
+pub fn find_undefined_macros(
+    cfg: &KatexConfig,
+    root: &Path,
+    definitions: Vec<MacroDefinition>,
+    environments: &Environments,
+    chapters: &[ChapterFormulas],
+    failures: Option<&[RenderFailure]>,
+) -> Vec<UndefinedMacro> {
+    let strict_cfg = KatexConfig {
+        throw_on_error: true,
+        ..cfg.clone()
+    };
+    let mut extra_opts = cfg.build_extra_opts();
+    extra_opts.cache = strict_cfg.render_cache(root, &definitions);
+    let (inline_opts, display_opts) = strict_cfg.build_opts_from_macros(macro_map(definitions));
+    let failed: Option<HashSet<_>> = failures.filter(|_| cfg.throw_on_error).map(|failures| {
+        failures
+            .iter()
+            .map(|failure| {
+                (
+                    failure.chapter.as_deref(),
+                    failure.line,
+                    failure.formula.as_str(),
+                )
+            })
+            .collect()
+    });
+
+    let mut undefined = Vec::new();
+    for chapter in chapters {
+        let mut inline_opts = inline_opts.clone();
+        let mut display_opts = display_opts.clone();
+        let mut chapter_macros = chapter.definitions.clone();
+        if cfg.persist_macros {
+            for (_, _, formula) in &chapter.formulas {
+                chapter_macros.extend(parse_global_macros(formula));
+            }
+        }
+        let environments = chapter_environments(environments, &chapter_macros);
+        let mut variant = Vec::new();
+        for definition in chapter_macros {
+            if definition.environment().is_none() {
+                let expansion = definition.katex_expansion();
+                inline_opts.add_macro(definition.name.clone(), expansion.clone());
+                display_opts.add_macro(definition.name.clone(), expansion.clone());
+                variant.push((definition.name, expansion));
+            }
+        }
+        let mut extra_opts = extra_opts.clone();
+        extra_opts.cache = extra_opts.cache.map(|cache| cache.with_macros(&variant));
+
+        let found: Vec<_> = chapter
+            .formulas
+            .par_iter()
+            .filter(|(line, _, formula)| {
+                failed
+                    .as_ref()
+                    .is_none_or(|failed| failed.contains(&(Some(chapter.path), *line, formula)))
+            })
+            .map(|(line, display, formula)| {
+                let formula = environments.expand(formula).unwrap_or((*formula).into());
+                let opts = match display {
+                    true => &display_opts,
+                    false => &inline_opts,
+                };
+                undefined_in(&formula, *display, opts.clone(), &extra_opts)
+                    .into_iter()
+                    .map(|name| UndefinedMacro {
+                        name,
+                        chapter: chapter.path.to_owned(),
+                        line: *line,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        undefined.extend(found.into_iter().flatten());
+    }
+    undefined
+}
+
+/// Undefined control sequences in `formula`, displayed or not,
+/// rendered with `opts` within the limits of `extra_opts`.
+/// KaTeX stops at the first one, so each is defined as empty in turn
+/// to find the next.
+fn undefined_in(
+    formula: &str,
+    display: bool,
+    mut opts: Opts,
+    extra_opts: &ExtraOpts,
+) -> Vec<String> {
+    let mut undefined = Vec::new();
+    while undefined.len() < MAX_UNDEFINED_PER_FORMULA {
+        // Only the formula as written may be in the cache.
+        let rendered = match undefined.is_empty() {
+            true => render_cached(formula, &opts, extra_opts, display),
+            false => render_limited(formula, &opts, extra_opts),
+        };
+        let Err(why) = rendered else {
+            break;
+        };
+        let Some(name) = undefined_control_sequence(&why) else {
+            break;
+        };
+        if undefined.contains(&name) {
+            break;
+        }
+        opts.add_macro(name.clone(), String::new());
+        undefined.push(name);
+    }
+    undefined
+}
+
+/// Name of the control sequence in a KaTeX "Undefined control sequence" error.
+fn undefined_control_sequence(message: &str) -> Option<String> {
+    let (_, rest) = message.split_once("Undefined control sequence: ")?;
+    let (name, _) = rest.split_once(" at position")?;
+    // The message may escape the backslash.
+    let name = name.trim_start_matches('\\');
+    (!name.is_empty()).then(|| format!("\\{name}"))
+}
@@ -0,0 +1,87 @@
+//! Rendering with a time limit.
+//!
+//! KaTeX cannot be interrupted, so each thread hands its formulas over to
+//! a helper thread with its own JS engine and waits for it until the time
+//! limit. A helper that runs late is abandoned, and replaced for the next
+//! formulas. Abandoned helpers stop when the preprocessor exits.
+use std::{
+    cell::RefCell,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use katex::Opts;
+
+use super::*;
+
+/// Helper thread rendering formulas for one thread.
+struct Helper {
+    /// Formulas to render, with their options.
+    jobs: mpsc::Sender<(String, Opts)>,
+    /// Results, in the order of `jobs`.
+    outcomes: mpsc::Receiver<Result<String, String>>,
+}
+
+impl Helper {
+    /// Spawn a helper, and wait for its JS engine to be ready,
+    /// so that setting it up does not count towards the time limit.
+    fn spawn() -> Result<Self, String> {
+        let (jobs, job_receiver) = mpsc::channel::<(String, Opts)>();
+        let (outcome_sender, outcomes) = mpsc::channel();
+        thread::Builder::new()
+            .name("katex-helper".into())
+            .spawn(move || {
+                if outcome_sender
+                    .send(render_guarded("", &Opts::default()))
+                    .is_err()
+                {
+                    return;
+                }
+                for (item, opts) in job_receiver {
+                    if outcome_sender.send(render_guarded(&item, &opts)).is_err() {
+                        return;
+                    }
+                }
+            })
+            .map_err(|why| format!("couldn't start a thread to render: {why}"))?;
+        match outcomes.recv() {
+            Ok(Ok(_)) => Ok(Self { jobs, outcomes }),
+            Ok(Err(why)) => Err(why),
+            Err(_) => Err("the rendering thread stopped while starting".into()),
+        }
+    }
+}
+
+thread_local! {
+    /// Helper of the current thread, replaced after running late.
+    static HELPER: RefCell<Option<Helper>> = const { RefCell::new(None) };
+}
+
+/// Render `item` with KaTeX following `opts`, giving up after `timeout`.
+pub fn render_with_timeout(item: &str, opts: &Opts, timeout: Duration) -> Result<String, String> {
+    HELPER.with_borrow_mut(|helper| {
+        let current = match helper {
+            Some(current) => current,
+            None => helper.insert(Helper::spawn()?),
+        };
+        let outcome = match current.jobs.send((item.to_owned(), opts.clone())) {
+            Ok(()) => current.outcomes.recv_timeout(timeout),
+            Err(_) => Err(RecvTimeoutError::Disconnected),
+        };
+        match outcome {
+            Ok(rendered) => rendered,
+            Err(RecvTimeoutError::Timeout) => {
+                *helper = None;
+                Err(format!(
+                    "rendering took longer than `render-timeout-ms` = {}",
+                    timeout.as_millis()
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                *helper = None;
+                Err("the rendering thread stopped unexpectedly".into())
+            }
+        }
+    })
+}
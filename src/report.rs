@@ -0,0 +1,236 @@
+//! Report of book macros that are never used,
+//! and of control sequences that KaTeX does not know.
+use std::fmt;
+
+use mdbook_preprocessor::book::BookItem;
+
+use super::*;
+
+/// Usage of macros across the book.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MacroReport {
+    /// Macros from `macros` never used, even through other macros.
+    pub unused: Vec<String>,
+    /// Uses of control sequences that fail as undefined, in book order.
+    pub undefined: Vec<UndefinedMacro>,
+}
+
+/// Use of an undefined control sequence.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UndefinedMacro {
+    /// Name of the control sequence, e.g., `\foo`.
+    pub name: String,
+    /// Path of the chapter using it.
+    pub chapter: PathBuf,
+    /// Line where the math block using it starts.
+    pub line: usize,
+}
+
+impl fmt::Display for UndefinedMacro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`{}` at {}:{}",
+            self.name,
+            self.chapter.display(),
+            self.line
+        )
+    }
+}
+
+impl fmt::Display for MacroReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.unused.is_empty() && self.undefined.is_empty() {
+            return writeln!(f, "All macros are used and defined.");
+        }
+        if !self.unused.is_empty() {
+            writeln!(f, "Unused macros:")?;
+            for name in &self.unused {
+                writeln!(f, "    `{name}`")?;
+            }
+        }
+        if !self.undefined.is_empty() {
+            writeln!(f, "Undefined control sequences:")?;
+            for undefined in &self.undefined {
+                writeln!(f, "    {undefined}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Math blocks of a chapter.
+#[derive(Clone, Debug)]
+pub struct ChapterFormulas<'a> {
+    /// Path of the chapter.
+    pub path: &'a Path,
+    /// Macros defined in `katex-macros` blocks of the chapter.
+    pub definitions: Vec<MacroDefinition>,
+    /// Line, whether it is displayed, and content of each math block.
+    pub formulas: Vec<(usize, bool, &'a str)>,
+}
+
+/// Find the math blocks of the chapter at `path` with `raw_content`.
+pub fn chapter_formulas<'a>(
+    path: &'a Path,
+    raw_content: &'a str,
+    extra_opts: &ExtraOpts,
+) -> ChapterFormulas<'a> {
+    let definitions = find_chapter_macros(raw_content)
+        .into_iter()
+        .filter_map(|block| block.definitions.ok())
+        .flatten()
+        .collect();
+    let formulas = get_render_tasks(raw_content, "", extra_opts)
+        .into_iter()
+        .filter_map(|task| {
+            let (item, display) = match task {
+                Render::Text(_) => return None,
+                Render::InlineTask(item) => (item, false),
+                Render::DisplayTask(item) => (item, true),
+            };
+            let (line, _) = line_and_column(raw_content, offset_in(raw_content, item));
+            Some((line, display, item))
+        })
+        .collect();
+    ChapterFormulas {
+        path,
+        definitions,
+        formulas,
+    }
+}
+
+/// Report the macros from `macros` that `chapters` never use,
+/// and the undefined control sequences in `chapters`,
+/// given as paths and raw contents.
+/// `failures` are those of rendering `chapters`, if they were just rendered.
+pub fn macro_report(
+    cfg: &KatexConfig,
+    root: &Path,
+    chapters: &[(&Path, &str)],
+    failures: Option<&[RenderFailure]>,
+) -> Result<MacroReport> {
+    let extra_opts = cfg.build_extra_opts();
+    let book_macros = load_macro_definitions(root, &cfg.macros)?;
+    let (definitions, environments) = cfg.load_definitions(root)?;
+    let chapters: Vec<_> = chapters
+        .iter()
+        .map(|(path, raw_content)| chapter_formulas(path, raw_content, &extra_opts))
+        .collect();
+
+    let mut used = BTreeSet::new();
+    for chapter in &chapters {
+        for (_, _, formula) in &chapter.formulas {
+            used.extend(macro_uses(formula));
+        }
+        for definition in &chapter.definitions {
+            used.extend(macro_uses(&definition.expansion));
+        }
+    }
+    // Macros used by used macros are used too.
+    let mut pending: Vec<String> = used.iter().cloned().collect();
+    while let Some(name) = pending.pop() {
+        for definition in book_macros.iter().filter(|d| d.name == name) {
+            for name in macro_uses(&definition.expansion) {
+                if used.insert(name.clone()) {
+                    pending.push(name);
+                }
+            }
+        }
+    }
+
+    let mut unused = Vec::new();
+    for definition in &book_macros {
+        let environment_end = matches!(definition.environment(), Some((_, false)));
+        if !environment_end && !used.contains(&definition.name) {
+            unused.push(definition.name.clone());
+        }
+    }
+
+    let (macros, _) = split_environments(definitions);
+    let undefined = find_undefined_macros(cfg, root, macros, &environments, &chapters, failures);
+    Ok(MacroReport { unused, undefined })
+}
+
+/// Without `pre-render`, KaTeX is unavailable to find undefined control sequences.
+#[cfg(not(feature = "pre-render"))]
+pub fn find_undefined_macros(
+    _: &KatexConfig,
+    _: &Path,
+    _: Vec<MacroDefinition>,
+    _: &Environments,
+    _: &[ChapterFormulas],
+    _: Option<&[RenderFailure]>,
+) -> Vec<UndefinedMacro> {
+    warn!("Undefined control sequences are only reported with the `pre-render` feature.");
+    Vec::new()
+}
+
+/// Paths and raw contents of the chapters of `book`, for the macro report.
+pub fn report_chapters(book: &Book) -> Vec<(PathBuf, String)> {
+    book.iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some((chapter.path.clone()?, chapter.content.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Log the macro report for `chapters`, given as paths and raw contents,
+/// as warnings. They were just rendered with `failures`.
+pub fn warn_macro_report(
+    chapters: &[(PathBuf, String)],
+    cfg: &KatexConfig,
+    root: &Path,
+    failures: &[RenderFailure],
+) -> Result<()> {
+    let chapters: Vec<_> = chapters
+        .iter()
+        .map(|(path, content)| (path.as_path(), content.as_str()))
+        .collect();
+    let report = macro_report(cfg, root, &chapters, Some(failures))?;
+    for name in &report.unused {
+        warn!("Unused macro `{name}`.");
+    }
+    for undefined in &report.undefined {
+        warn!("Undefined control sequence {undefined}.");
+    }
+    Ok(())
+}
+
+/// Macro report for the book at `root`, whose chapters are all the
+/// Markdown files in its source directory, for the `html` renderer.
+pub fn book_macro_report(root: &Path) -> Result<MacroReport> {
+    let (cfg, src) = get_book_config(root)?;
+    let paths = markdown_files(&src)?;
+    let contents = paths
+        .iter()
+        .map(|path| std::fs::read_to_string(src.join(path)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let chapters: Vec<_> = paths
+        .iter()
+        .map(PathBuf::as_path)
+        .zip(contents.iter().map(String::as_str))
+        .collect();
+    macro_report(&cfg, root, &chapters, None)
+}
+
+/// Markdown files under `dir`, except `SUMMARY.md`, relative to `dir`, sorted.
+pub fn markdown_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in std::fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else if path.extension().is_some_and(|e| e == "md") && path != Path::new("SUMMARY.md")
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
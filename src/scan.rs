@@ -2,7 +2,7 @@
 use super::*;
 
 /// A pair of strings are delimiters.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Delimiter {
     /// Left delimiter.
     pub left: String,
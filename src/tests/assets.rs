@@ -0,0 +1,81 @@
+use super::*;
+
+#[test]
+fn test_embedded_stylesheet_header() {
+    let cfg = KatexConfig {
+        assets: "embed".into(),
+        ..KatexConfig::default()
+    };
+    let stylesheet = cfg.stylesheet();
+    debug_assert_eq!(stylesheet, Stylesheet::Embedded);
+    let header = stylesheet.header(Some(Path::new("intro.md")));
+    assert!(header.starts_with("<link rel=\"stylesheet\" href=\"katex/katex.min.css\">\n"));
+    let header = stylesheet.header(Some(Path::new("part/chapter/section.md")));
+    assert!(header.starts_with("<link rel=\"stylesheet\" href=\"../../katex/katex.min.css\">\n"));
+    // In `print.html`, the link is rewritten from the root.
+    assert!(header.contains(r#"href = path_to_root + "katex/katex.min.css";</script>"#));
+    assert!(header.ends_with("\n\n"));
+}
+
+#[test]
+fn test_no_css_overrides_assets() {
+    let cfg = KatexConfig {
+        no_css: true,
+        assets: "embed".into(),
+        ..KatexConfig::default()
+    };
+    debug_assert_eq!(cfg.stylesheet().header(None), "");
+}
+
+#[test]
+fn test_cdn_stylesheet() {
+    debug_assert_eq!(
+        KATEX_HEADER,
+        format!("<link rel=\"stylesheet\" href=\"{KATEX_CDN_STYLESHEET}\">\n\n")
+    );
+    #[cfg(feature = "pre-render")]
+    assert!(KATEX_CDN_STYLESHEET.contains(&format!("/katex@{}/", katex::KATEX_VERSION)));
+}
+
+#[cfg(all(feature = "embed-assets", feature = "pre-render"))]
+#[test]
+fn test_embedded_assets_match_renderer() {
+    debug_assert_eq!(EMBEDDED_ASSETS_VERSION, katex::KATEX_VERSION);
+}
+
+#[test]
+fn test_inject_css_math_only() {
+    let cfg = KatexConfig {
+        inject_css: "math".into(),
+        ..KatexConfig::default()
+    };
+    let extra_opts = cfg.build_extra_opts();
+    let injection = cfg.css_injection();
+    let stylesheet = cfg.stylesheet();
+    debug_assert_eq!(
+        stylesheet.chapter_header(injection, None, "No math, only \\$5.", &extra_opts),
+        ""
+    );
+    debug_assert_eq!(
+        stylesheet.chapter_header(injection, None, "Some $x$.", &extra_opts),
+        KATEX_HEADER
+    );
+}
+
+#[test]
+fn test_inject_css_once() {
+    let cfg = KatexConfig {
+        inject_css: "once".into(),
+        ..KatexConfig::default()
+    };
+    let extra_opts = cfg.build_extra_opts();
+    let stylesheet = cfg.stylesheet();
+    debug_assert_eq!(
+        stylesheet.chapter_header(cfg.css_injection(), None, "Some $x$.", &extra_opts),
+        ""
+    );
+    debug_assert_eq!(
+        stylesheet.import_css().unwrap(),
+        format!("@import url(\"{KATEX_CDN_STYLESHEET}\");\n")
+    );
+}
@@ -0,0 +1,58 @@
+use super::*;
+
+fn test_cache(name: &str, max_size: u64) -> RenderCache {
+    RenderCache::new(test_dir(name), max_size, "{}")
+}
+
+#[test]
+fn test_cache_keys() {
+    let cache = test_cache("cache-keys", u64::MAX);
+    assert_eq!(cache.key("x", false), cache.key("x", false));
+    assert_ne!(cache.key("x", false), cache.key("x", true));
+    assert_ne!(cache.key("x", false), cache.key("y", false));
+    let other_options = RenderCache::new(cache.dir.clone(), u64::MAX, r#"{"leqno":true}"#);
+    assert_ne!(cache.key("x", false), other_options.key("x", false));
+
+    let macros = [(r"\R".to_owned(), r"\mathbb{R}".to_owned())];
+    let with_macros = cache.with_macros(&macros);
+    assert_ne!(cache.key("x", false), with_macros.key("x", false));
+    assert_eq!(with_macros, cache.with_macros(&macros));
+}
+
+#[test]
+fn test_cache_entries() {
+    let cache = test_cache("cache-entries", u64::MAX);
+    let key = cache.key("x", false);
+    assert_eq!(cache.get(&key), None);
+    cache.put(&key, "<span>x</span>");
+    assert_eq!(cache.get(&key).as_deref(), Some("<span>x</span>"));
+    cache.put(&key, "<span>x</span>");
+    assert_eq!(cache.get(&key).as_deref(), Some("<span>x</span>"));
+
+    clear_cache(&cache.dir).unwrap();
+    assert_eq!(cache.get(&key), None);
+    clear_cache(&cache.dir).unwrap();
+}
+
+#[test]
+fn test_cache_trim() {
+    let mut cache = test_cache("cache-trim", u64::MAX);
+    let keys: Vec<_> = ["a", "b", "c"]
+        .iter()
+        .map(|item| cache.key(item, false))
+        .collect();
+    for key in &keys {
+        cache.put(key, "0123456789");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    // Using the oldest entry makes it the most recently used.
+    assert!(cache.get(&keys[0]).is_some());
+    assert_eq!(cache.trim().unwrap(), 0);
+
+    cache.max_size = 2 * (CACHE_FORMAT.len() as u64 + 10);
+    assert_eq!(cache.trim().unwrap(), 1);
+    assert!(cache.get(&keys[0]).is_some());
+    assert_eq!(cache.get(&keys[1]), None);
+    assert!(cache.get(&keys[2]).is_some());
+    clear_cache(&cache.dir).unwrap();
+}
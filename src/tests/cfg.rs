@@ -0,0 +1,242 @@
+use super::*;
+
+fn config_with_env(book_toml: &str, vars: &[(&str, &str)]) -> KatexConfig {
+    let ctx = PreprocessorContext::new("".into(), book_toml.parse().unwrap(), "html".into());
+    let vars = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+    get_config_with_env(&ctx, vars).unwrap()
+}
+
+#[test]
+fn test_env_overrides_book_toml() {
+    let cfg = config_with_env(
+        r#"
+        [preprocessor.katex]
+        throw-on-error = true
+        macros = "macros.txt"
+        "#,
+        &[
+            ("MDBOOK_KATEX_THROW_ON_ERROR", "false"),
+            ("MDBOOK_KATEX_MACROS", "ci/macros.txt"),
+            ("MDBOOK_KATEX_MAX_EXPAND", "10"),
+            ("MDBOOK_KATEX_JOBS", "4"),
+            ("MDBOOK_OTHER", "ignored"),
+        ],
+    );
+    assert!(!cfg.throw_on_error);
+    assert!(matches!(
+        &cfg.macros,
+        Some(Macros::One(MacroSource::Path(path))) if path == "ci/macros.txt"
+    ));
+    debug_assert_eq!(cfg.max_expand, 10);
+    debug_assert_eq!(cfg.jobs, Some(4));
+}
+
+#[test]
+fn test_env_overrides_without_table() {
+    let cfg = config_with_env(
+        "[book]\ntitle = \"Book\"",
+        &[
+            ("MDBOOK_KATEX_PRE_RENDER", "false"),
+            (
+                "MDBOOK_KATEX_INLINE_DELIMITER",
+                r#"{ left = "\\(", right = "\\)" }"#,
+            ),
+        ],
+    );
+    assert!(!cfg.pre_render);
+    debug_assert_eq!(cfg.inline_delimiter.left, r"\(");
+    debug_assert_eq!(cfg.inline_delimiter.right, r"\)");
+}
+
+#[test]
+fn test_invalid_config_is_an_error() {
+    let config_error = |book_toml: &str, vars: &[(&str, &str)]| {
+        let ctx = PreprocessorContext::new("".into(), book_toml.parse().unwrap(), "html".into());
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        get_config_with_env(&ctx, vars).unwrap_err().to_string()
+    };
+    let why = config_error("", &[("MDBOOK_KATEX_JOBS", "four")]);
+    assert!(why.contains("`MDBOOK_KATEX_JOBS`"), "{why}");
+    let why = config_error("", &[("MDBOOK_KATEX_THROW_ON_ERROR", "nope")]);
+    assert!(why.contains("`MDBOOK_KATEX_THROW_ON_ERROR`"), "{why}");
+    let why = config_error("[preprocessor.katex]\nmax-expand = \"many\"", &[]);
+    assert!(why.contains("max-expand"), "{why}");
+    let why = config_error("[preprocessor.katex]\nmacro-packages = [\"physcs\"]", &[]);
+    assert!(why.contains("`physcs`"), "{why}");
+    for (option, value) in [
+        ("mode", "hybird"),
+        ("assets", "local"),
+        ("inject-css", "head"),
+        ("error-display", "box"),
+        ("include-src", "html"),
+    ] {
+        let why = config_error(
+            &format!("[preprocessor.katex]\n{option} = \"{value}\""),
+            &[],
+        );
+        assert!(
+            why.contains(&format!("`{value}` is not a valid choice for `{option}`")),
+            "{why}"
+        );
+    }
+    let why = config_error("", &[("MDBOOK_KATEX_MODE", "hybird")]);
+    assert!(why.contains("`MDBOOK_KATEX_MODE`"), "{why}");
+    let why = config_error("[preprocessor.katex]\nerror-display = \"template\"", &[]);
+    assert!(why.contains("requires `error-template`"), "{why}");
+    // The template may come from elsewhere than the variable.
+    let ctx = PreprocessorContext::new(
+        "".into(),
+        "[preprocessor.katex]\nerror-template = \"<b>{source}</b>\""
+            .parse()
+            .unwrap(),
+        "html".into(),
+    );
+    let vars = [("MDBOOK_KATEX_ERROR_DISPLAY".into(), "template".into())];
+    assert!(get_config_with_env(&ctx, vars).is_ok());
+}
+
+#[test]
+fn test_renderer_profiles() {
+    let book_toml = r#"
+    [preprocessor.katex]
+    output = "html"
+
+    [preprocessor.katex.renderer.epub]
+    output = "mathml"
+
+    [preprocessor.katex.renderer.markdown]
+    pre-render = false
+    "#;
+    let config_for = |renderer: &str| {
+        let ctx = PreprocessorContext::new("".into(), book_toml.parse().unwrap(), renderer.into());
+        get_config_with_env(&ctx, []).unwrap()
+    };
+
+    let html = config_for("html");
+    debug_assert_eq!(html.output, "html");
+    assert!(html.pre_render);
+    let epub = config_for("epub");
+    debug_assert_eq!(epub.output, "mathml");
+    assert!(epub.pre_render);
+    let markdown = config_for("markdown");
+    debug_assert_eq!(markdown.output, "html");
+    assert!(!markdown.pre_render);
+}
+
+#[test]
+fn test_shared_config_file() {
+    let root = test_dir("shared-config");
+    let book = root.join("book");
+    std::fs::create_dir_all(root.join("shared")).unwrap();
+    std::fs::create_dir_all(&book).unwrap();
+    std::fs::write(
+        root.join("shared/katex.toml"),
+        r#"
+        macros = "macros.txt"
+        throw-on-error = false
+        trust = true
+        inline-delimiter = { left = "\\(", right = "\\)" }
+
+        [renderer.epub]
+        macros = ["epub-macros.txt", { '\R' = '\mathbf{R}' }]
+        "#,
+    )
+    .unwrap();
+
+    let book_cfg = r#"
+    [preprocessor.katex]
+    config-file = "../shared/katex.toml"
+    trust = false
+    "#
+    .parse()
+    .unwrap();
+    let ctx = PreprocessorContext::new(book, book_cfg, "html".into());
+    let vars = [("MDBOOK_KATEX_THROW_ON_ERROR".into(), "true".into())];
+    let cfg = get_config_with_env(&ctx, vars).unwrap();
+
+    assert!(matches!(
+        &cfg.macros,
+        Some(Macros::One(MacroSource::Path(path))) if path == "../shared/macros.txt"
+    ));
+    debug_assert_eq!(cfg.inline_delimiter.left, r"\(");
+    assert!(!cfg.trust);
+    assert!(cfg.throw_on_error);
+
+    let ctx = PreprocessorContext::new(ctx.root, ctx.config, "epub".into());
+    let cfg = get_config_with_env(&ctx, []).unwrap();
+    assert!(matches!(
+        &cfg.macros,
+        Some(Macros::Many(sources)) if matches!(
+            &sources[0],
+            MacroSource::Path(path) if path == "../shared/epub-macros.txt"
+        )
+    ));
+}
+
+#[test]
+fn test_missing_shared_config_file() {
+    let book_cfg = r#"
+    [preprocessor.katex]
+    config-file = "does/not/exist.toml"
+    "#
+    .parse()
+    .unwrap();
+    let ctx = PreprocessorContext::new("".into(), book_cfg, "html".into());
+    assert!(get_config_with_env(&ctx, []).is_err());
+}
+
+#[test]
+fn test_schema_covers_all_options() {
+    let schema = serde_json::to_value(config_schema()).unwrap();
+    let properties = schema["properties"].as_object().unwrap();
+    let defaults = toml::Table::try_from(KatexConfig::default()).unwrap();
+    for key in defaults.keys() {
+        assert!(properties.contains_key(key), "`{key}` missing from schema");
+    }
+    debug_assert_eq!(
+        properties["throw-on-error"]["description"],
+        "Whether to let KaTeX throw a ParseError for invalid LaTeX."
+    );
+    // Defaults are valid values.
+    assert!(properties["max-size"].get("default").is_none());
+    let mode = &properties["mode"];
+    assert!(mode["enum"].as_array().unwrap().contains(&mode["default"]));
+    let include_src = &schema["$defs"]["IncludeSrc"]["anyOf"][1];
+    debug_assert_eq!(
+        include_src["enum"],
+        serde_json::json!(["data", "annotation", "attribute"])
+    );
+}
+
+#[test]
+fn test_mode() {
+    debug_assert_eq!(KatexConfig::default().mode(), Mode::PreRender);
+    let cfg = KatexConfig {
+        pre_render: false,
+        ..KatexConfig::default()
+    };
+    debug_assert_eq!(cfg.mode(), Mode::Escape);
+    for (mode, expected) in [
+        ("escape", Mode::Escape),
+        ("hybrid", Mode::Hybrid),
+        ("invalid", Mode::PreRender),
+    ] {
+        let cfg = KatexConfig {
+            mode: Some(mode.into()),
+            pre_render: false,
+            ..KatexConfig::default()
+        };
+        debug_assert_eq!(cfg.mode(), expected);
+    }
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_deprecated_get_config() {
+    let book_cfg = "[preprocessor.katex]\ntrust = true".parse().unwrap();
+    assert!(get_config(&book_cfg).unwrap().trust);
+}
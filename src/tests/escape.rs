@@ -8,10 +8,13 @@ fn test_render(raw_content: &str) -> (String, String) {
 
 fn test_render_with_cfg(raw_contents: &[&str], cfg: KatexConfig) -> (String, Vec<String>) {
     let extra_opts = cfg.build_extra_opts();
+    let environments = cfg.load_environments("");
     let stylesheet_header = KATEX_HEADER.to_owned();
     let rendered = raw_contents
         .iter()
-        .map(|raw_content| process_chapter_escape(raw_content, &extra_opts, &stylesheet_header))
+        .map(|raw_content| {
+            process_chapter_escape(raw_content, &extra_opts, &stylesheet_header, &environments)
+        })
         .collect();
     (stylesheet_header, rendered)
 }
@@ -57,3 +60,37 @@ fn test_escape_vmatrix() {
     let expected_output = stylesheet_header + r"$$\\begin{vmatrix}a&b\\\\c&d\\end{vmatrix}$$";
     debug_assert_eq!(expected_output, rendered_content);
 }
+
+#[test]
+fn test_escape_chapter_macros() {
+    let raw_content = "```katex-macros\n\\R: \\mathbb{R}\n\\lt: <\n```\n$\\R$";
+    let (stylesheet_header, rendered_content) = test_render(raw_content);
+    let expected_output = stylesheet_header
+        + r#"<script type="application/json" class="katex-macros">{"\\R":"\\mathbb{R}","\\lt":"\u003c"}</script>"#
+        + "\n\n$\\\\R$";
+    debug_assert_eq!(expected_output, rendered_content);
+}
+
+#[test]
+fn test_escape_environments() {
+    let raw_content = r"```katex-macros
+\begin{pair}[2]: \left( #1, #2
+\end{pair}: \right)
+\R: \mathbb{R}
+```
+$\begin{pair}{x}{y}\end{pair}$";
+    let (stylesheet_header, rendered_content) = test_render(raw_content);
+    let expected_output = stylesheet_header
+        + r#"<script type="application/json" class="katex-macros">{"\\R":"\\mathbb{R}"}</script>
+
+$\\left( x, y\\right)$"#;
+    debug_assert_eq!(expected_output, rendered_content);
+}
+
+#[test]
+fn test_escape_with_invalid_macros() {
+    let cfg: KatexConfig = toml::from_str(r#"macros = "missing-macros.txt""#).unwrap();
+    assert!(cfg.load_definitions("").is_err());
+    let (stylesheet_header, rendered) = test_render_with_cfg(&["$x_1$"], cfg);
+    debug_assert_eq!(rendered[0], stylesheet_header + r"$x\_1$");
+}
@@ -0,0 +1,393 @@
+use std::path::{Path, PathBuf};
+
+use crate::{line_and_column, macros::*, tests::test_dir};
+
+fn parse(source: &str) -> Result<Vec<MacroDefinition>, MacroErrors> {
+    parse_macros(source, Path::new("macros.txt"))
+}
+
+#[test]
+fn test_parse_macros() {
+    let definitions = parse(
+        r"% Notation.
+\grad:{\nabla}
+
+\R[2]: {\mathbb{R}^{#1 \times #2}} % real matrices
+\percent: 50\%
+\long: \left(
+    #1 %
+    \right)
+",
+    )
+    .unwrap();
+    let summary: Vec<_> = definitions
+        .iter()
+        .map(|d| (d.name.as_str(), d.n_args, d.expansion.as_str(), d.line))
+        .collect();
+    debug_assert_eq!(
+        summary,
+        [
+            (r"\grad", None, r"{\nabla}", 2),
+            (r"\R", Some(2), r"{\mathbb{R}^{#1 \times #2}}", 4),
+            (r"\percent", None, r"50\%", 5),
+            (r"\long", None, r"\left( #1\right)", 6),
+        ]
+    );
+}
+
+#[test]
+fn test_explicit_argument_count() {
+    let definitions = parse(r"\first[2]: #1").unwrap();
+    debug_assert_eq!(definitions[0].katex_expansion(), r"\@firstoftwo{#1}{#2}");
+    let definitions = parse(r"\both[2]: #1#2").unwrap();
+    debug_assert_eq!(definitions[0].katex_expansion(), r"#1#2");
+}
+
+#[test]
+fn test_macros_errors() {
+    let errors = parse(
+        r"\foo
+  \bar: x
+\bar: y
+\bar: z
+text
+\baz[2]: #3
+\qux[x]: 1",
+    )
+    .unwrap_err();
+    debug_assert_eq!(
+        errors.to_string(),
+        r"invalid macros file
+macros.txt:1: expected `:` after `\foo`
+macros.txt:2: continuation line without a macro definition before it
+macros.txt:4: duplicate definition of `\bar`, first defined at line 3
+macros.txt:6: `\baz` declares 2 argument(s) but uses `#3`
+macros.txt:7: the argument count of `\qux` must be a number from 0 to 9, found `x`"
+    );
+    // Lines not starting with `\` used to be ignored.
+    let definitions = parse("Macros for the book.\n\\R: \\mathbb{R}").unwrap();
+    debug_assert_eq!(definitions.len(), 1);
+}
+
+#[test]
+fn test_parse_tex_macros() {
+    let definitions = parse_tex_macros(
+        r"\documentclass{article}
+\usepackage{amsmath} % \newcommand{\ignored}{x}
+\newcommand{\R}{\mathbb{R}}
+\newcommand*\norm[1]{\left\lVert #1 \right\rVert}
+\renewcommand{\R}{\mathbb{R}^{#1}}
+\def\pair#1#2{\left(#1, #2\right)}
+\DeclareMathOperator{\tr}{tr}
+\DeclareMathOperator*{\argmax}{arg\,max}
+\newcommand{\set}[1]{\left\{ #1 \right\}}
+\begin{document}",
+        Path::new("preamble.tex"),
+    )
+    .unwrap();
+    let summary: Vec<_> = definitions
+        .iter()
+        .map(|d| (d.name.as_str(), d.n_args, d.expansion.as_str(), d.line))
+        .collect();
+    debug_assert_eq!(
+        summary,
+        [
+            (r"\R", None, r"\mathbb{R}^{#1}", 5),
+            (r"\norm", Some(1), r"\left\lVert #1 \right\rVert", 4),
+            (r"\pair", Some(2), r"\left(#1, #2\right)", 6),
+            (r"\tr", None, r"\operatorname{tr}", 7),
+            (r"\argmax", None, r"\operatorname*{arg\,max}", 8),
+            (r"\set", Some(1), r"\left\{ #1 \right\}", 9),
+        ]
+    );
+}
+
+#[test]
+fn test_tex_macros_errors() {
+    let errors = parse_tex_macros(
+        r"\newcommand{\R}{\mathbb{R}}
+\newcommand{\R}{\mathbb{R}}
+\newcommand{\opt}[2][x]{#1#2}
+\def\delim#1.{#1}
+\newcommand{\open}{\left(",
+        Path::new("preamble.tex"),
+    )
+    .unwrap_err();
+    debug_assert_eq!(
+        errors.to_string(),
+        r"invalid macros file
+preamble.tex:2: in `\newcommand`: `\R` is already defined at line 1, use `\renewcommand` to redefine it
+preamble.tex:3: in `\newcommand`: optional arguments are not supported by KaTeX, in `\opt`
+preamble.tex:4: in `\def`: delimited parameters `#1.` are not supported, in `\delim`
+preamble.tex:5: in `\newcommand`: unbalanced braces"
+    );
+}
+
+#[test]
+fn test_parse_global_macros() {
+    let definitions = parse_global_macros(
+        r"\def\local{a} \gdef\g#1{#1} \global\def\h{b} \global\newcommand{\n}[2]{#2} \gdef\g{c} \gdef\bad",
+    );
+    let names: Vec<_> = definitions.iter().map(|d| d.name.as_str()).collect();
+    debug_assert_eq!(names, [r"\g", r"\h", r"\n"]);
+    debug_assert_eq!(definitions[0].expansion, "c");
+    debug_assert_eq!(definitions[2].n_args, Some(2));
+}
+
+#[test]
+fn test_parse_map_macros() {
+    let toml = parse_map_macros(
+        r#"'\R' = '\mathbb{R}'
+grad = '\nabla'"#,
+        Path::new("macros.toml"),
+    )
+    .unwrap();
+    let json = parse_map_macros(
+        r#"{"\\R": "\\mathbb{R}", "grad": "\\nabla"}"#,
+        Path::new("macros.json"),
+    )
+    .unwrap();
+    debug_assert_eq!(toml, json);
+    let names: Vec<_> = toml.iter().map(|d| d.name.as_str()).collect();
+    debug_assert_eq!(names, [r"\R", r"\grad"]);
+}
+
+#[test]
+fn test_load_macro_sources_in_order() {
+    let root = test_dir("macro-sources");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("macros.txt"),
+        "\\R: \\mathbb{R}\n\\N: \\mathbb{N}",
+    )
+    .unwrap();
+    std::fs::write(root.join("preamble.tex"), r"\renewcommand{\R}{\mathbf{R}}").unwrap();
+    let book_cfg: mdbook_preprocessor::config::Config = r#"
+    [preprocessor.katex]
+    macros = ["macros.txt", "preamble.tex", { '\N' = '\mathbf{N}', '\Z' = '\mathbb{Z}' }]
+    "#
+    .parse()
+    .unwrap();
+    let macros = book_cfg
+        .get::<crate::cfg::Macros>("preprocessor.katex.macros")
+        .unwrap();
+
+    let definitions = load_macro_definitions(&root, &macros).unwrap();
+    let summary: Vec<_> = definitions
+        .iter()
+        .map(|d| (d.name.as_str(), d.expansion.as_str()))
+        .collect();
+    debug_assert_eq!(
+        summary,
+        [
+            (r"\R", r"\mathbf{R}"),
+            (r"\N", r"\mathbf{N}"),
+            (r"\Z", r"\mathbb{Z}")
+        ]
+    );
+    debug_assert_eq!(
+        get_macro_paths(&root, &macros),
+        [root.join("macros.txt"), root.join("preamble.tex")]
+    );
+}
+
+#[test]
+fn test_find_chapter_macros() {
+    let raw_content = r"# Chapter
+
+````markdown
+```katex-macros
+\ignored: x
+```
+````
+
+```katex-macros
+\R: \mathbb{R}  % reals
+```
+Text.
+~~~~ katex-macros
+\bad
+~~~~
+
+    ```katex-macros
+    \indented: code
+    ```
+
+  ```katex-macros
+  \N: \mathbb{N}
+    \cup \{0\}
+  ```
+";
+    let blocks = find_chapter_macros(raw_content);
+    debug_assert_eq!(blocks.len(), 3);
+    debug_assert_eq!(
+        &raw_content[blocks[0].range.clone()],
+        "```katex-macros\n\\R: \\mathbb{R}  % reals\n```\n"
+    );
+    let definitions = blocks[0].definitions.as_ref().unwrap();
+    debug_assert_eq!(definitions[0].name, r"\R");
+    debug_assert_eq!(definitions[0].line, 10);
+    debug_assert_eq!(
+        blocks[1].definitions.as_ref().unwrap_err().to_string(),
+        "invalid macros file\nline 14: expected `:` after `\\bad`"
+    );
+    // Fences indented by 4 spaces are indented code blocks,
+    // and the content of indented fences is dedented.
+    let definitions = blocks[2].definitions.as_ref().unwrap();
+    debug_assert_eq!(definitions.len(), 1);
+    debug_assert_eq!(definitions[0].name, r"\N");
+    debug_assert_eq!(definitions[0].expansion, r"\mathbb{N} \cup \{0\}");
+    debug_assert_eq!(definitions[0].line, 22);
+}
+
+#[test]
+fn test_replace_chapter_macros() {
+    let raw_content = r"```katex-macros
+\R: \mathbb{R}
+```
+~~~katex-macros
+\bad
+~~~
+```katex-macros
+\N: \mathbb{N}
+```
+
+$\R \N$
+";
+    for replacement in [chapter_macros_script, |_: &[MacroDefinition]| String::new()] {
+        let chapter = replace_chapter_macros(raw_content, replacement);
+        debug_assert_eq!(chapter.definitions.len(), 2);
+        debug_assert_eq!(chapter.invalid.len(), 1);
+        debug_assert_eq!(chapter.replaced.len(), 2);
+        let offset = chapter.content.find('$').unwrap();
+        let original = chapter.original_offset(offset);
+        debug_assert_eq!(line_and_column(raw_content, original), (11, 1));
+        debug_assert_eq!(&raw_content[original..], "$\\R \\N$\n");
+    }
+}
+
+#[test]
+fn test_load_macro_packages() {
+    let packages = ["physics", "siunitx-lite"].map(String::from);
+    let definitions = load_macro_packages(&packages).unwrap();
+    let qty = definitions.iter().find(|d| d.name == r"\qty").unwrap();
+    debug_assert_eq!(qty.n_args, Some(2));
+    assert!(definitions.iter().any(|d| d.name == r"\dv"));
+    assert!(definitions.iter().any(|d| d.name == r"\SI"));
+    let packages = ["physics", "physcs"].map(String::from);
+    let why = load_macro_packages(&packages).unwrap_err().to_string();
+    assert!(why.contains("`physcs` is not a valid choice for `macro-packages`"));
+}
+
+#[test]
+fn test_parse_environments() {
+    let definitions = parse_macros(
+        r"\begin{system}: \left\{\begin{aligned}
+\end{system}: \end{aligned}\right.
+\begin{pair}[2]: \left( #1, #2
+\end{pair}: \right)",
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    debug_assert_eq!(definitions[0].environment(), Some(("system", true)));
+    debug_assert_eq!(definitions[1].environment(), Some(("system", false)));
+    let (macros, environments) = split_environments(definitions);
+    assert!(macros.is_empty());
+    debug_assert_eq!(environments.0["pair"].n_args, 2);
+
+    let tex_definitions = parse_tex_macros(
+        r"\newenvironment{system}{\left\{\begin{aligned}}{\end{aligned}\right.}
+\newenvironment{pair}[2]{\left( #1, #2}{\right)}",
+        Path::new("preamble.tex"),
+    )
+    .unwrap();
+    let (_, tex_environments) = split_environments(tex_definitions);
+    debug_assert_eq!(tex_environments, environments);
+
+    let errors = parse_macros(r"\end{pair}[1]: #1", Path::new("macros.txt")).unwrap_err();
+    debug_assert_eq!(
+        errors.to_string(),
+        "invalid macros file\nmacros.txt:1: `\\end{pair}` cannot take arguments, only `\\begin{pair}` can"
+    );
+}
+
+#[test]
+fn test_expand_environments() {
+    let definitions = parse_macros(
+        r"\begin{system}: \left\{\begin{aligned}
+\end{system}: \end{aligned}\right.
+\begin{pair}[2]: \begin{system} #1 \\ #2
+\end{pair}: \end{system}
+\begin{loop}: \begin{loop}
+\end{loop}: \end{loop}",
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    let (_, environments) = split_environments(definitions);
+
+    debug_assert_eq!(
+        environments.expand(r"\begin{matrix}x\end{matrix}").unwrap(),
+        r"\begin{matrix}x\end{matrix}"
+    );
+    debug_assert_eq!(
+        environments
+            .expand(r"\begin{pair}{x=1}{y}\end{pair}")
+            .unwrap(),
+        r"\left\{\begin{aligned} x=1 \\ y\end{aligned}\right."
+    );
+
+    let error = |formula| environments.expand(formula).unwrap_err();
+    debug_assert_eq!(
+        error(r"x \begin{pair}{x}"),
+        EnvironmentError {
+            offset: Some(2),
+            message: r"`\begin{pair}` expects 2 argument(s) in braces".into()
+        }
+    );
+    debug_assert_eq!(
+        error(r"\begin{system} x"),
+        EnvironmentError {
+            offset: Some(0),
+            message: r"`\begin{system}` is not closed".into()
+        }
+    );
+    debug_assert_eq!(
+        error(r"\begin{system} x \end{pair}").message,
+        r"expected `\end{system}`, found `\end{pair}`"
+    );
+    debug_assert_eq!(
+        error(r"x \end{system}").message,
+        r"`\end{system}` without `\begin{system}` before it"
+    );
+    debug_assert_eq!(error(r"\begin{loop}\end{loop}").offset, None);
+}
+
+#[test]
+fn test_macro_descriptions() {
+    let definitions = parse_tex_macros(
+        r"\newcommand{\R}{\mathbb{R}} % the real numbers
+\newcommand{\N}{
+    \mathbb{N}} % the natural numbers
+\def\Z{\mathbb{Z}}",
+        Path::new("preamble.tex"),
+    )
+    .unwrap();
+    let descriptions: Vec<_> = definitions
+        .iter()
+        .map(|d| d.description.as_deref())
+        .collect();
+    debug_assert_eq!(
+        descriptions,
+        [Some("the real numbers"), Some("the natural numbers"), None]
+    );
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_deprecated_get_macro_path() {
+    debug_assert_eq!(
+        get_macro_path("book", &Some("macros.txt".into())),
+        Some(PathBuf::from("book/macros.txt"))
+    );
+    debug_assert_eq!(get_macro_path("book", &None), None);
+}
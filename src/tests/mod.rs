@@ -14,7 +14,22 @@ fn test_support_html() {
     assert!(preprocessor.supports_renderer("other_renderer").unwrap())
 }
 
+/// Empty directory for the test `name`, unique to this test run.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mdbook-katex-test-{name}-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+mod assets;
+mod cache;
+mod cfg;
 mod escape;
+mod macros;
+mod notation;
+mod rename;
+mod report;
 
 #[cfg(feature = "pre-render")]
 mod render;
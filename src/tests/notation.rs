@@ -0,0 +1,112 @@
+use mdbook_preprocessor::book::{BookItem, Chapter};
+
+use super::*;
+
+fn definitions() -> Vec<MacroDefinition> {
+    parse_macros(
+        r"% Sets.
+\R: {\mathbb R}  % the real numbers
+\abs: \left| #1 \right|  % absolute value, $\abs{-1} = 1$
+\pair[2]: (#1, #2)  % ordered pair, or
+    % couple
+\begin{system}: \left\{\begin{aligned}  % system of | equations
+\end{system}: \end{aligned}\right.",
+        Path::new("macros.txt"),
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_notation_content() {
+    let content = notation_content("Notation", &definitions(), &Delimiter::same("$".into()));
+    debug_assert_eq!(
+        content,
+        r"# Notation
+
+| Notation | Command | Description |
+| --- | --- | --- |
+| $\R$ | `\R` | the real numbers |
+| $\abs{\#1}$ | `\abs{#1}` | absolute value, $\abs{-1} = 1$ |
+| $\pair{\#1}{\#2}$ | `\pair{#1}{#2}` | ordered pair, or couple |
+| $\begin{system} \cdots \end{system}$ | `\begin{system} ... \end{system}` | system of \| equations |
+"
+    );
+}
+
+#[test]
+fn test_notation_chapter() {
+    let delimiter = Delimiter::same("$".into());
+    let mut book = Book::new();
+    add_notation_chapter(&mut book, "Symbols", &[], &delimiter);
+    let chapters: Vec<_> = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter),
+            _ => None,
+        })
+        .collect();
+    debug_assert_eq!(chapters.len(), 1);
+    debug_assert_eq!(chapters[0].name, "Symbols");
+    debug_assert_eq!(
+        chapters[0].path.as_deref(),
+        Some(Path::new(NOTATION_CHAPTER))
+    );
+    debug_assert_eq!(chapters[0].content, "# Symbols\n\nNo macros are defined.\n");
+
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "Mine",
+        String::new(),
+        NOTATION_CHAPTER,
+        vec![],
+    ));
+    add_notation_chapter(&mut book, "Symbols", &definitions(), &delimiter);
+    debug_assert_eq!(book.iter().count(), 1);
+}
+
+#[test]
+fn test_notation_pipes() {
+    let definitions =
+        parse_macros(r"\|: \Vert  % norm | $\|x\|$", Path::new("macros.txt")).unwrap();
+    let content = notation_content("Notation", &definitions, &Delimiter::same("$".into()));
+    assert!(content.ends_with("| $\\|$ | `\\\\|` | norm \\| $\\|x\\|$ |\n"));
+
+    let cfg = KatexConfig {
+        notation_chapter: Some("Notation".into()),
+        include_src: IncludeSrc::Enabled(true),
+        ..KatexConfig::default()
+    };
+    let notation_book = || {
+        let mut book = Book::new();
+        add_notation_chapter(&mut book, "Notation", &definitions, &cfg.inline_delimiter);
+        book
+    };
+    let row = |book: &Book| {
+        let content = book
+            .iter()
+            .find_map(|item| match item {
+                BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+                _ => None,
+            })
+            .unwrap();
+        content.lines().last().unwrap().to_owned()
+    };
+    let ctx = PreprocessorContext::new("".into(), Default::default(), "html".into());
+
+    let mut book = notation_book();
+    process_all_chapters_escape(&mut book, &cfg, &Stylesheet::None, &ctx).unwrap();
+    debug_assert_eq!(
+        row(&book),
+        r"| $\\&#124;$ | `\\|` | norm \| $\\&#124;x\\&#124;$ |"
+    );
+
+    #[cfg(feature = "pre-render")]
+    {
+        let mut book = notation_book();
+        process_all_chapters_prerender(&mut book, &cfg, &Stylesheet::None, &ctx).unwrap();
+        let row = row(&book);
+        assert!(row.contains(r#"<data class="katex-src" value="\&#124;">"#));
+        // Only the cell separators and the escaped pipes are left.
+        debug_assert_eq!(row.matches('|').count(), 6);
+    }
+}
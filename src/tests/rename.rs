@@ -0,0 +1,111 @@
+use crate::rename::*;
+
+use super::*;
+
+#[test]
+fn test_rename_control_sequence() {
+    let rename = |source, syntax| rename_control_sequence(source, r"\R", r"\Reals", syntax);
+    debug_assert_eq!(
+        rename(r"\R^n \Rho \\R \\\R_+ x\R", Syntax::Tex),
+        r"\Reals^n \Rho \\R \\\Reals_+ x\Reals"
+    );
+    debug_assert_eq!(
+        rename(r#"{"\\R": "\\mathbb{R}", "\\Rho": "\\R"}"#, Syntax::Json),
+        r#"{"\\Reals": "\\mathbb{R}", "\\Rho": "\\Reals"}"#
+    );
+    debug_assert_eq!(
+        rename(r#"'\R' = "\\R^{#1}""#, Syntax::Toml),
+        r#"'\Reals' = "\\Reals^{#1}""#
+    );
+    assert!(matches!(rename(r"\Rho", Syntax::Tex), Cow::Borrowed(_)));
+}
+
+#[test]
+fn test_rename_in_chapter() {
+    let raw_content = r"Text \R, `$\R$`, and $\R^n$.
+
+```katex-macros
+\RR: \R^2
+```
+
+```tex
+\R
+```
+
+$$
+\R
+$$";
+    let expected = r"Text \R, `$\R$`, and $\Reals^n$.
+
+```katex-macros
+\RR: \Reals^2
+```
+
+```tex
+\R
+```
+
+$$
+\Reals
+$$";
+    let extra_opts = KatexConfig::default().build_extra_opts();
+    debug_assert_eq!(
+        rename_in_chapter(raw_content, r"\R", r"\Reals", &extra_opts),
+        expected
+    );
+}
+
+#[test]
+fn test_rename_in_tilde_fence() {
+    let raw_content = "~~~katex-macros\n\\foo: \\text{if $\\R$}\n~~~\n$\\R$\n";
+    let expected = "~~~katex-macros\n\\foo: \\text{if $\\Reals$}\n~~~\n$\\Reals$\n";
+    let extra_opts = KatexConfig::default().build_extra_opts();
+    debug_assert_eq!(
+        rename_in_chapter(raw_content, r"\R", r"\Reals", &extra_opts),
+        expected
+    );
+}
+
+#[test]
+fn test_rename_macro() {
+    let root = test_dir("rename-macro");
+    std::fs::create_dir_all(root.join("src")).unwrap();
+    std::fs::write(
+        root.join("book.toml"),
+        r#"
+        [preprocessor.katex]
+        macros = ["macros.txt", "notation.json"]
+        "#,
+    )
+    .unwrap();
+    std::fs::write(root.join("macros.txt"), "\\R: \\mathbb{R}\n").unwrap();
+    std::fs::write(root.join("notation.json"), r#"{"\\C": "\\R^2"}"#).unwrap();
+    std::fs::write(root.join("src/a.md"), "$\\R$ and $\\C$\n").unwrap();
+    std::fs::write(root.join("src/b.md"), "No math.\n").unwrap();
+
+    assert!(rename_macro(&root, r"\R", r"\C").is_err());
+    assert!(rename_macro(&root, r"\R", r"\R2").is_err());
+
+    let rewrites = rename_macro(&root, r"\R", r"\Reals").unwrap();
+    let paths: Vec<_> = rewrites.iter().map(|rewrite| &rewrite.path).collect();
+    debug_assert_eq!(
+        paths,
+        [
+            root.join("src/a.md"),
+            root.join("macros.txt"),
+            root.join("notation.json")
+        ]
+        .iter()
+        .collect::<Vec<_>>()
+    );
+    debug_assert_eq!(rewrites[2].after, r#"{"\\C": "\\Reals^2"}"#);
+    debug_assert_eq!(
+        rewrites[0].unified_diff(&root),
+        "--- a/src/a.md
++++ b/src/a.md
+@@ -1 +1 @@
+-$\\R$ and $\\C$
++$\\Reals$ and $\\C$
+"
+    );
+}
@@ -18,7 +18,9 @@ fn test_render_with_cfg(
     cfg: KatexConfig,
 ) -> (String, Vec<String>) {
     let (inline_opts, display_opts) = cfg.build_opts_from_macros(macros);
-    let extra_opts = cfg.build_extra_opts();
+    let mut extra_opts = cfg.build_extra_opts();
+    let (_, environments) = cfg.load_definitions("").unwrap();
+    extra_opts.cache = cfg.render_cache(Path::new(""), &[]);
     let stylesheet_header = KATEX_HEADER.to_owned();
     let rendered = raw_contents
         .iter()
@@ -29,6 +31,7 @@ fn test_render_with_cfg(
                 display_opts.clone(),
                 &stylesheet_header,
                 &extra_opts,
+                &environments,
             )
         })
         .collect();
@@ -107,11 +110,12 @@ fn test_macro_file_loading() {
     "#;
 
     let book_cfg = cfg_str.parse().unwrap();
-    let cfg = get_config(&book_cfg).unwrap();
+    let ctx = PreprocessorContext::new(PathBuf::from("book"), book_cfg, "html".into());
+    let cfg = get_context_config(&ctx).unwrap();
 
     debug_assert_eq!(
-        get_macro_path(PathBuf::from("book"), &cfg.macros),
-        Some(PathBuf::from("book/macros.txt")) // We supply a root, just like the preproccessor context does
+        get_macro_paths(PathBuf::from("book"), &cfg.macros),
+        [PathBuf::from("book/macros.txt")] // We supply a root, just like the preproccessor context does
     );
 }
 
@@ -237,7 +241,7 @@ $$";
         &[raw_content],
         HashMap::new(),
         KatexConfig {
-            include_src: true,
+            include_src: IncludeSrc::Enabled(true),
             ..KatexConfig::default()
         },
     );
@@ -293,3 +297,527 @@ fn test_inline_rendering_w_custom_delimiter() {
 
 #[cfg(not(feature = "duktape"))]
 mod not_duktape;
+
+#[test]
+fn test_macros_with_explicit_argument_count() {
+    let mut macros = HashMap::new();
+    for definition in parse_macros(r"\first[2]: #1", Path::new("macros.txt")).unwrap() {
+        macros.insert(definition.name.clone(), definition.katex_expansion());
+    }
+    let raw_content_no_macro = r"Some text, $a + c$.";
+    let raw_content_macro = r"Some text, $\first{a}{b} + c$.";
+    let (_, rendered) = test_render_with_macro(&[raw_content_macro, raw_content_no_macro], macros);
+    debug_assert_eq!(rendered[0], rendered[1]);
+}
+
+#[test]
+fn test_chapter_macros() {
+    let mut macros = HashMap::new();
+    macros.insert(String::from(r"\R"), String::from(r"\mathbb{Q}"));
+    let raw_content_no_macro = r"Some text, $\nabla f(x) \in \mathbb{R}^n$, and more text.";
+    let raw_content_macro = r"```katex-macros
+\grad: \nabla
+\R: \mathbb{R}
+```
+Some text, $\grad f(x) \in \R^n$, and more text.";
+    let (_, rendered) = test_render_with_macro(&[raw_content_macro, raw_content_no_macro], macros);
+    debug_assert_eq!(rendered[0], rendered[1]);
+}
+
+#[test]
+fn test_persist_macros() {
+    let raw_content_persisted = r"Define $\gdef\foo#1{#1^2}$, use $\foo{x}$ and $$\foo{y}$$.";
+    let raw_content_expanded = r"Define $\gdef\foo#1{#1^2}$, use $x^2$ and $$y^2$$.";
+    let cfg = KatexConfig {
+        persist_macros: true,
+        ..KatexConfig::default()
+    };
+    let (_, rendered) = test_render_with_cfg(
+        &[raw_content_persisted, raw_content_expanded],
+        HashMap::new(),
+        cfg,
+    );
+    debug_assert_eq!(rendered[0], rendered[1]);
+
+    let (_, rendered) = test_render_with_macro(
+        &[raw_content_persisted, raw_content_expanded],
+        HashMap::new(),
+    );
+    debug_assert_ne!(rendered[0], rendered[1]);
+}
+
+#[test]
+fn test_macro_packages_render() {
+    for (package, _) in MACRO_PACKAGES {
+        let definitions = macro_package(package).unwrap();
+        let macros: HashMap<_, _> = definitions
+            .iter()
+            .map(|definition| (definition.name.clone(), definition.katex_expansion()))
+            .collect();
+        let mut builder = katex::Opts::builder();
+        builder.throw_on_error(true).macros(macros);
+        for definition in definitions {
+            let n_args = definition.n_args.unwrap_or(max_arg(&definition.expansion));
+            let formula = format!("{}{}", definition.name, "{x}".repeat(n_args));
+            let rendered = katex::render_with_opts(&formula, builder.clone().build().unwrap());
+            assert!(
+                rendered.is_ok(),
+                "`{formula}` from `{package}`: {rendered:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_user_macros_override_packages() {
+    let cfg: KatexConfig = toml::from_str(
+        r#"macro-packages = ["physics"]
+macros = { '\abs' = '|#1|' }"#,
+    )
+    .unwrap();
+    let (inline_opts, display_opts) = cfg.build_opts("").unwrap();
+    let extra_opts = cfg.build_extra_opts();
+    let rendered = process_chapter_prerender(
+        r"$\abs{x} \norm{x}$",
+        inline_opts,
+        display_opts,
+        "",
+        &extra_opts,
+        &Environments::default(),
+    );
+    let (_, expected) =
+        test_render_with_macro(&[r"$|x| \left\lVert x \right\rVert$"], HashMap::new());
+    debug_assert_eq!(rendered, expected[0].trim_start_matches(KATEX_HEADER));
+}
+
+#[test]
+fn test_environments() {
+    let cfg: KatexConfig = toml::from_str(
+        r#"[macros]
+'\begin{system}' = '\left\{\begin{aligned}'
+'\end{system}' = '\end{aligned}\right.'"#,
+    )
+    .unwrap();
+    let raw_content_environment = r"$$\begin{system} x &= 1 \\ y &= 2 \end{system}$$
+```katex-macros
+\begin{pair}[2]: \left( #1, #2
+\end{pair}: \right)
+```
+$\begin{pair}{x}{y}\end{pair}$";
+    let raw_content_expanded = r"$$\left\{\begin{aligned} x &= 1 \\ y &= 2 \end{aligned}\right.$$
+$\left( x, y\right)$";
+    let (_, rendered) = test_render_with_cfg(
+        &[raw_content_environment, raw_content_expanded],
+        HashMap::new(),
+        cfg,
+    );
+    debug_assert_eq!(rendered[0], rendered[1]);
+}
+
+#[test]
+fn test_environments_include_src() {
+    let mut cfg: KatexConfig = toml::from_str(
+        r#"include-src = "attribute"
+[macros]
+'\begin{system}' = '\left\{\begin{aligned}'
+'\end{system}' = '\end{aligned}\right.'"#,
+    )
+    .unwrap();
+    let raw_content = r"$\begin{system} x &= 1 \end{system}$ $\begin{system} \foo \end{system}$";
+    let (_, rendered) = test_render_with_cfg(&[raw_content], HashMap::new(), cfg.clone());
+    assert!(rendered[0].contains(r#"data-latex="\begin{system} x &amp;= 1 \end{system}""#));
+    assert!(rendered[0].contains(r"$\begin{system} \foo \end{system}$"));
+    assert!(!rendered[0].contains(r"\left\{"));
+
+    // The browser does not know custom environments.
+    cfg.mode = Some("hybrid".into());
+    let (_, rendered) = test_render_with_cfg(&[raw_content], HashMap::new(), cfg);
+    assert!(rendered[0].contains(r"\\left\\{\\begin{aligned} \\foo"));
+}
+
+#[test]
+fn test_notation_chapter_renders() {
+    let definitions = parse_macros(
+        r"\R: \mathbb{R}  % the real numbers
+\pair[2]: (#1, #2)",
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    let content = notation_content("Notation", &definitions, &Delimiter::same("$".into()));
+    let (_, rendered) = test_render_with_macro(&[&content], macro_map(definitions));
+    assert!(!rendered[0].contains("katex-error"));
+    assert!(rendered[0].contains(r#"<span class="mord mathbb">R</span>"#));
+    assert!(rendered[0].contains("#1"));
+}
+
+#[test]
+fn test_render_cache() {
+    let dir = test_dir("render-cache");
+    clear_cache(&dir).unwrap();
+    let cfg = KatexConfig {
+        cache: true,
+        cache_dir: dir.to_string_lossy().into_owned(),
+        ..KatexConfig::default()
+    };
+    let raw_content = r"$\R$ and $$\R^2$$";
+    let macros = HashMap::from([(r"\R".into(), r"\mathbb{R}".into())]);
+    let (_, uncached) = test_render_with_macro(&[raw_content], macros.clone());
+    let (_, first) = test_render_with_cfg(&[raw_content], macros.clone(), cfg.clone());
+    let (_, second) = test_render_with_cfg(&[raw_content], macros.clone(), cfg.clone());
+    assert_eq!(uncached, first);
+    assert_eq!(first, second);
+
+    // Later builds take formulas from the cache.
+    let cache = cfg.render_cache(Path::new(""), &[]).unwrap();
+    cache.put(&cache.key(r"\R", false), "<span>cached</span>");
+    let (_, rendered) = test_render_with_cfg(&[raw_content], macros, cfg);
+    assert!(rendered[0].contains("<span>cached</span> and "));
+    clear_cache(&dir).unwrap();
+}
+
+#[test]
+fn test_deduplicate_formulas() {
+    let cfg = KatexConfig::default();
+    let (inline_opts, display_opts) = cfg.build_opts_from_macros(HashMap::new());
+    let extra_opts = cfg.build_extra_opts();
+    let environments = Environments::default();
+    let chapters = [
+        "$x$ and $x$ and $$x$$",
+        "$x$ and $y$",
+        "```katex-macros\n\\x: y\n```\n$x$ and $\\x$",
+    ];
+    let mut queue = FormulaQueue::default();
+    let queued: Vec<_> = chapters
+        .iter()
+        .map(|raw_content| {
+            let scanned = ScannedChapter::new(raw_content, None, "", &extra_opts, &environments);
+            queue.push_scanned(scanned)
+        })
+        .collect();
+    let rendered = queue.render(&inline_opts, &display_opts, &extra_opts);
+    // `x` inline and displayed, `y`, then `x` and `\x` with the chapter macro.
+    assert_eq!(rendered.len(), 5);
+    for (raw_content, queued) in chapters.iter().zip(queued) {
+        let expected = process_chapter_prerender(
+            raw_content,
+            inline_opts.clone(),
+            display_opts.clone(),
+            "",
+            &extra_opts,
+            &environments,
+        );
+        assert_eq!(queued.splice(&rendered), expected);
+    }
+}
+
+#[test]
+fn test_process_all_chapters() {
+    use mdbook_preprocessor::book::{BookItem, Chapter};
+
+    let contents = [
+        "# A\n\n$x$ and $$x$$",
+        "# B\n\n```katex-macros\n\\x: y\n```\n$x$ and $\\x$",
+        "# C\n\nNo math.",
+    ];
+    let mut book = Book::new();
+    for (index, content) in contents.iter().enumerate() {
+        book.push_item(Chapter {
+            name: format!("Chapter {index}"),
+            content: content.to_string(),
+            path: Some(format!("{index}.md").into()),
+            ..Default::default()
+        });
+    }
+    let cfg = KatexConfig::default();
+    let ctx = PreprocessorContext::new("".into(), Default::default(), "html".into());
+    process_all_chapters_prerender(&mut book, &cfg, &Stylesheet::Cdn, &ctx).unwrap();
+
+    let (_, expected) = test_render_with_cfg(&contents, HashMap::new(), cfg);
+    let processed: Vec<_> = book
+        .iter()
+        .filter_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(processed, expected);
+}
+
+#[test]
+fn test_render_failures() {
+    use mdbook_preprocessor::book::Chapter;
+
+    let mut book = Book::new();
+    book.push_item(Chapter {
+        name: "Failures".into(),
+        content:
+            "# Failures\n\n```katex-macros\n\\x: y\n```\n\n$\\x$ and $\\foo$\n\n$$\n\\frac{1}\n$$"
+                .into(),
+        path: Some("failures.md".into()),
+        ..Default::default()
+    });
+    let cfg = KatexConfig::default();
+    let ctx = PreprocessorContext::new("".into(), Default::default(), "html".into());
+    let failures = process_all_chapters_prerender(&mut book, &cfg, &Stylesheet::Cdn, &ctx).unwrap();
+
+    debug_assert_eq!(failures.len(), 2);
+    debug_assert_eq!(failures[0].chapter, Some("failures.md".into()));
+    debug_assert_eq!((failures[0].line, failures[0].column), (7, 11));
+    debug_assert_eq!(failures[0].formula, r"\foo");
+    debug_assert_eq!(
+        failures[0].message,
+        r"KaTeX parse error: Undefined control sequence: \foo at position 1"
+    );
+    debug_assert_eq!((failures[1].line, failures[1].column), (9, 3));
+    debug_assert_eq!(failures[1].formula, "\n\\frac{1}\n");
+    debug_assert_eq!(
+        failures[1].message,
+        "KaTeX parse error: Unexpected end of input in a macro argument, expected '}' at end of input"
+    );
+
+    assert!(report_failures(&failures, false).is_ok());
+    assert!(report_failures(&failures, true).is_err());
+    assert!(report_failures(&[], true).is_ok());
+}
+
+#[test]
+fn test_invalid_chapter_macros() {
+    use mdbook_preprocessor::book::{BookItem, Chapter};
+
+    let mut book = Book::new();
+    book.push_item(Chapter {
+        name: "Invalid".into(),
+        content: "$\\x$\n\n```katex-macros\n\\x: y\n\\bad\n```\n".into(),
+        path: Some("invalid.md".into()),
+        ..Default::default()
+    });
+    let cfg = KatexConfig {
+        inject_css: "never".into(),
+        ..KatexConfig::default()
+    };
+    let ctx = PreprocessorContext::new("".into(), Default::default(), "html".into());
+    let failures = process_all_chapters_prerender(&mut book, &cfg, &Stylesheet::Cdn, &ctx).unwrap();
+    debug_assert_eq!(failures.len(), 2);
+    debug_assert_eq!(failures[0].formula, r"\x");
+    debug_assert_eq!((failures[1].line, failures[1].column), (3, 1));
+    debug_assert_eq!(
+        failures[1].message,
+        r"invalid `katex-macros` block, line 5: expected `:` after `\bad`"
+    );
+    assert!(report_failures(&failures, true).is_err());
+    // The invalid block is left as it is.
+    let BookItem::Chapter(chapter) = book.iter().next().unwrap() else {
+        panic!("Not a chapter");
+    };
+    assert!(chapter
+        .content
+        .ends_with("```katex-macros\n\\x: y\n\\bad\n```\n"));
+}
+
+#[test]
+fn test_error_display() {
+    let raw_content = r#"$\foo<"x">$"#;
+    let render_with = |error_display: &str, error_template: &str| {
+        let cfg = KatexConfig {
+            error_display: error_display.into(),
+            error_template: error_template.into(),
+            ..KatexConfig::default()
+        };
+        let (header, mut rendered) = test_render_with_cfg(&[raw_content], HashMap::new(), cfg);
+        rendered
+            .pop()
+            .unwrap()
+            .strip_prefix(&header)
+            .unwrap()
+            .to_owned()
+    };
+
+    debug_assert_eq!(render_with("raw", ""), raw_content);
+    debug_assert_eq!(
+        render_with("span", ""),
+        r#"<span class="katex-error" title="KaTeX parse error: Undefined control sequence: \foo at position 1" style="color: #cc0000; font-family: monospace; white-space: pre-wrap;">&#92;foo&lt;&quot;x&quot;&gt;</span>"#
+    );
+    debug_assert_eq!(
+        render_with("template", "<code>{source}</code>{ {message}"),
+        r"<code>&#92;foo&lt;&quot;x&quot;&gt;</code>{ KaTeX parse error: Undefined control sequence: \foo at position 1"
+    );
+    // A template is required.
+    debug_assert_eq!(render_with("template", ""), raw_content);
+}
+
+#[test]
+fn test_error_display_markdown() {
+    let cfg = KatexConfig {
+        error_display: "span".into(),
+        ..KatexConfig::default()
+    };
+    let raw_content = r"$\left\{ a \, b*c*$";
+    let (header, rendered) = test_render_with_cfg(&[raw_content], HashMap::new(), cfg);
+    let rendered = rendered[0].strip_prefix(&header).unwrap();
+    assert!(rendered.ends_with(r#"pre-wrap;">&#92;left&#92;{ a &#92;, b&#42;c&#42;</span>"#));
+}
+
+#[test]
+fn test_include_src_modes() {
+    let raw_content = r#"$a<b \text{"} \&$"#;
+    let render_with = |include_src: IncludeSrc, output: &str| {
+        let cfg = KatexConfig {
+            include_src,
+            output: output.into(),
+            ..KatexConfig::default()
+        };
+        let (_, mut rendered) = test_render_with_cfg(&[raw_content], HashMap::new(), cfg);
+        rendered.pop().unwrap()
+    };
+    let escaped = r"a&lt;b \text{&quot;} \&amp;";
+
+    let data = render_with(IncludeSrc::Mode("data".into()), "html");
+    assert!(data.contains(&format!(
+        r#"<data class="katex-src" value="{escaped}"><span class="katex">"#
+    )));
+    assert_eq!(data, render_with(IncludeSrc::Enabled(true), "html"));
+
+    let annotation = render_with(IncludeSrc::Mode("annotation".into()), "html");
+    assert!(annotation.contains(&format!(
+        r#"<span class="katex"><span class="katex-mathml"><math xmlns="http://www.w3.org/1998/Math/MathML"><semantics><mrow></mrow><annotation encoding="application/x-tex">{escaped}</annotation></semantics></math></span><span class="katex-html""#
+    )));
+    // KaTeX annotates its own MathML.
+    let annotation = render_with(IncludeSrc::Mode("annotation".into()), "htmlAndMathml");
+    assert_eq!(
+        annotation,
+        render_with(IncludeSrc::Enabled(false), "htmlAndMathml")
+    );
+    assert!(annotation.contains(r#"<annotation encoding="application/x-tex">"#));
+
+    let attribute = render_with(IncludeSrc::Mode("attribute".into()), "html");
+    assert!(attribute.contains(&format!(
+        r#"<span class="katex" data-latex="{escaped}"><span class="katex-html""#
+    )));
+}
+
+#[test]
+fn test_resource_limits() {
+    use mdbook_preprocessor::book::BookItem;
+
+    let render_with = |raw_content: &str, cfg: KatexConfig| {
+        let ctx = PreprocessorContext::new("".into(), Default::default(), "html".into());
+        let mut book = Book::new();
+        book.push_item(mdbook_preprocessor::book::Chapter {
+            name: "Limits".into(),
+            content: raw_content.into(),
+            path: Some("limits.md".into()),
+            ..Default::default()
+        });
+        let failures =
+            process_all_chapters_prerender(&mut book, &cfg, &Stylesheet::None, &ctx).unwrap();
+        (book, failures)
+    };
+
+    let cfg = KatexConfig {
+        max_formula_length: Some(8),
+        ..KatexConfig::default()
+    };
+    let (_, failures) = render_with("$x^2$ and $x^2 + y^2 = z^2$", cfg);
+    debug_assert_eq!(failures.len(), 1);
+    debug_assert_eq!(failures[0].column, 12);
+    debug_assert_eq!(
+        failures[0].message,
+        "the formula is 15 bytes long, more than `max-formula-length` = 8"
+    );
+
+    // Each macro doubles the length of the expansion.
+    let mut bomb = String::from("```katex-macros\n\\a: xx\n");
+    for (previous, name) in ('a'..'q').zip('b'..='q') {
+        bomb.push_str(&format!("\\{name}: \\{previous}\\{previous}\n"));
+    }
+    bomb.push_str("```\n$x$ and $\\q$ and $y$");
+    let cfg = KatexConfig {
+        max_expand: i32::MAX,
+        render_timeout_ms: Some(100),
+        ..KatexConfig::default()
+    };
+    let (book, failures) = render_with(&bomb, cfg);
+    debug_assert_eq!(failures.len(), 1);
+    debug_assert_eq!(failures[0].formula, r"\q");
+    debug_assert_eq!(
+        failures[0].message,
+        "rendering took longer than `render-timeout-ms` = 100"
+    );
+    let BookItem::Chapter(chapter) = book.iter().next().unwrap() else {
+        panic!("Not a chapter");
+    };
+    assert!(chapter.content.starts_with(r#"<span class="katex">"#));
+    assert!(chapter.content.ends_with("</span>"));
+}
+
+#[test]
+fn test_catch_panic() {
+    debug_assert_eq!(catch_panic(|| Ok("fine".into())), Ok("fine".into()));
+    debug_assert_eq!(
+        catch_panic(|| panic!("engine broke on {}", "\\foo")),
+        Err(r"the JS engine panicked: engine broke on \foo".into())
+    );
+    debug_assert_eq!(
+        catch_panic(|| panic!("engine broke")),
+        Err("the JS engine panicked: engine broke".into())
+    );
+}
+
+#[test]
+fn test_hybrid_mode() {
+    use mdbook_preprocessor::book::{BookItem, Chapter};
+
+    let mut book = Book::new();
+    for (name, content) in [
+        (
+            "failing",
+            "```katex-macros\n\\x: y\n```\n\n$x$ and $\\foo_1$",
+        ),
+        ("fine", "$x$"),
+    ] {
+        book.push_item(Chapter {
+            name: name.into(),
+            content: content.into(),
+            path: Some(format!("{name}.md").into()),
+            ..Default::default()
+        });
+    }
+    let cfg = KatexConfig {
+        mode: Some("hybrid".into()),
+        fail_on_error: true,
+        ..KatexConfig::default()
+    };
+    debug_assert_eq!(cfg.mode(), Mode::Hybrid);
+    let ctx = PreprocessorContext::new("".into(), Default::default(), "html".into());
+    let failures = process_all_chapters_prerender(&mut book, &cfg, &Stylesheet::Cdn, &ctx).unwrap();
+    debug_assert_eq!(failures.len(), 1);
+    debug_assert_eq!(failures[0].formula, r"\foo_1");
+
+    let mut chapters = book.iter().filter_map(|item| match item {
+        BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+        _ => None,
+    });
+    let failing = chapters.next().unwrap();
+    assert!(failing.contains(r#"<span class="mord mathnormal">x</span>"#));
+    assert!(failing.contains(r#"<span class="katex-fallback">$\\foo\_1$</span>"#));
+    assert!(failing.contains(&format!(r#"let url = "{FALLBACK_KATEX_CDN}";"#)));
+    assert!(failing.contains(r#"load("/contrib/auto-render.min.js")"#));
+    assert!(failing.contains(r#""macros":{"\\x":"y"}"#));
+    let fine = chapters.next().unwrap();
+    assert!(!fine.contains(FALLBACK_CLASS));
+    assert!(!fine.contains("<script"));
+}
+
+#[test]
+fn test_fallback_url() {
+    let cfg = KatexConfig {
+        fallback_url: "katex/".into(),
+        ..KatexConfig::default()
+    };
+    let extra_opts = cfg.build_extra_opts();
+    debug_assert_eq!(extra_opts.fallback_url, "katex");
+    let script = fallback_bootstrap(&Default::default(), &extra_opts);
+    assert!(script.contains(r#"let url = "katex";"#));
+    assert!(script.contains("url = path_to_root + url;"));
+    assert!(script.contains(r#"load("/katex.min.js")"#));
+}
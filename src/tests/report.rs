@@ -0,0 +1,134 @@
+use super::*;
+
+#[test]
+fn test_book_macro_report() {
+    let root = test_dir("macro-report");
+    std::fs::create_dir_all(root.join("src/part")).unwrap();
+    std::fs::write(
+        root.join("book.toml"),
+        r#"
+        [preprocessor.katex]
+        macros = "macros.txt"
+        "#,
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("macros.txt"),
+        r"\R: \mathbb{R}
+\abs: \left| #1 \right|
+\norm: \left\lVert #1 \right\rVert
+\unused: x
+\begin{system}: \left\{\begin{aligned}
+\end{system}: \end{aligned}\right.
+\begin{pair}: (#1)
+\end{pair}:",
+    )
+    .unwrap();
+    std::fs::write(root.join("src/SUMMARY.md"), r"- [A]($\undefinedInSummary$)").unwrap();
+    std::fs::write(
+        root.join("src/a.md"),
+        r"# A
+
+$\norm{x} \in \R$
+
+`$\inCode$`",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("src/part/b.md"),
+        r"```katex-macros
+\local: \abs{#1}
+```
+
+$$
+\begin{system} x &= \local{y} \\ \foo &= \baz \end{system}
+$$",
+    )
+    .unwrap();
+
+    let report = book_macro_report(&root).unwrap();
+    debug_assert_eq!(report.unused, [r"\unused", r"\begin{pair}"]);
+    #[cfg(feature = "pre-render")]
+    debug_assert_eq!(
+        report.undefined,
+        [r"\foo", r"\baz"].map(|name| UndefinedMacro {
+            name: name.into(),
+            chapter: PathBuf::from("part/b.md"),
+            line: 5,
+        })
+    );
+    #[cfg(not(feature = "pre-render"))]
+    assert!(report.undefined.is_empty());
+}
+
+#[cfg(feature = "pre-render")]
+#[test]
+fn test_undefined_macros_within_limits() {
+    let chapters = [ChapterFormulas {
+        path: Path::new("a.md"),
+        definitions: Vec::new(),
+        formulas: vec![(1, false, r"\foo"), (2, false, r"\bar + \baz")],
+    }];
+    let cfg = KatexConfig {
+        max_formula_length: Some(8),
+        render_timeout_ms: Some(10_000),
+        ..KatexConfig::default()
+    };
+    let undefined = find_undefined_macros(
+        &cfg,
+        Path::new(""),
+        Vec::new(),
+        &Environments::default(),
+        &chapters,
+        None,
+    );
+    let names: Vec<_> = undefined
+        .iter()
+        .map(|undefined| undefined.name.as_str())
+        .collect();
+    // The second formula is too long to render.
+    debug_assert_eq!(names, [r"\foo"]);
+}
+
+#[cfg(feature = "pre-render")]
+#[test]
+fn test_undefined_macros_after_rendering() {
+    let chapters = [ChapterFormulas {
+        path: Path::new("a.md"),
+        definitions: Vec::new(),
+        formulas: vec![(1, false, r"\foo"), (2, false, r"\baz"), (3, false, "x")],
+    }];
+    let failures = [RenderFailure {
+        chapter: Some("a.md".into()),
+        line: 2,
+        column: 2,
+        formula: r"\baz".into(),
+        message: String::new(),
+    }];
+    let dir = test_dir("report-cache");
+    let cfg = KatexConfig {
+        cache: true,
+        cache_dir: dir.to_string_lossy().into_owned(),
+        ..KatexConfig::default()
+    };
+    let find = |failures| {
+        let environments = Environments::default();
+        let undefined = find_undefined_macros(
+            &cfg,
+            Path::new(""),
+            Vec::new(),
+            &environments,
+            &chapters,
+            failures,
+        );
+        undefined
+            .into_iter()
+            .map(|undefined| undefined.name)
+            .collect::<Vec<_>>()
+    };
+    // Only the formulas that failed to render are rendered again.
+    debug_assert_eq!(find(Some(&failures)), [r"\baz"]);
+    debug_assert_eq!(find(None), [r"\foo", r"\baz"]);
+    // Formulas that render are cached.
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_some());
+}